@@ -0,0 +1,37 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use peerrs_binarypack::binarypack::Unpacker;
+use peerrs_binarypack::decoder::{Decoder, Status};
+
+// Feeds the input in fragments whose sizes come from the first byte, and checks that the decoder
+// produces values of the same sizes as unpacking the input in one go
+fuzz_target!(|data: &[u8]| {
+    let (step, data) = match data.split_first() {
+        Some((&step, data)) => (step as usize % 16 + 1, data),
+        None => return,
+    };
+
+    let mut expected = vec![];
+    for value in Unpacker::new(data) {
+        match value {
+            Ok(value) => expected.push(value.pack().len()),
+            Err(_) => break,
+        }
+    }
+
+    let mut decoded = vec![];
+    let mut decoder = Decoder::new();
+    'chunks: for chunk in data.chunks(step) {
+        decoder.feed(chunk);
+        loop {
+            match decoder.decode() {
+                Ok(Status::Complete(value)) => decoded.push(value.pack().len()),
+                Ok(Status::NeedMore) => break,
+                Err(_) => break 'chunks,
+            }
+        }
+    }
+
+    assert_eq!(expected, decoded);
+});
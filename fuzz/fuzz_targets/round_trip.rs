@@ -0,0 +1,32 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use peerrs_binarypack::binarypack::{unpack, Unpacked};
+
+/// Compares values structurally, treating `Undefined` and NaN as equal to themselves even when
+/// they are map keys
+fn same(a: &Unpacked, b: &Unpacked) -> bool {
+    match (a, b) {
+        (Unpacked::Undefined, Unpacked::Undefined) => true,
+        (Unpacked::Float(x), Unpacked::Float(y)) => x.to_bits() == y.to_bits(),
+        (Unpacked::Double(x), Unpacked::Double(y)) => x.to_bits() == y.to_bits(),
+        (Unpacked::Array(x), Unpacked::Array(y)) => {
+            x.len() == y.len() && x.iter().zip(y).all(|(x, y)| same(x, y))
+        }
+        (Unpacked::Map(x), Unpacked::Map(y)) => {
+            x.len() == y.len()
+                && x
+                    .iter()
+                    .all(|(k, v)| y.iter().any(|(l, w)| same(k, l) && same(v, w)))
+        }
+        _ => a == b,
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(value) = unpack(data) {
+        let packed = value.pack();
+        let repacked = unpack(&packed).expect("packed output must unpack");
+        assert!(same(&value, &repacked), "{:?} != {:?}", value, repacked);
+    }
+});
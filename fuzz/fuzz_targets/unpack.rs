@@ -0,0 +1,48 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use peerrs_binarypack::binarypack::{unpack_with_options, InvalidUtf8, UnpackOptions, Unpacker};
+use peerrs_binarypack::cbor;
+use peerrs_binarypack::de::from_slice;
+use peerrs_binarypack::msgpack;
+use peerrs_binarypack::transcode::{
+    binarypack_to_cbor, binarypack_to_msgpack, msgpack_to_binarypack,
+};
+use peerrs_binarypack::validate::validate;
+
+fuzz_target!(|data: &[u8]| {
+    for value in Unpacker::new(data) {
+        if value.is_err() {
+            break;
+        }
+    }
+    let _ = Unpacker::new(data).unpack_ref();
+    let _ = msgpack::unpack(data);
+    if let Ok(converted) = binarypack_to_msgpack(data) {
+        assert!(msgpack::unpacker(&converted, UnpackOptions::default()).all(|v| v.is_ok()));
+    }
+    if let Ok(converted) = msgpack_to_binarypack(data) {
+        assert!(Unpacker::new(&converted).all(|v| v.is_ok()));
+    }
+    let _ = cbor::unpack(data);
+    if let Ok(converted) = binarypack_to_cbor(data) {
+        assert!(cbor::unpacker(&converted, UnpackOptions::default()).all(|v| v.is_ok()));
+    }
+    let _ = from_slice::<peerrs_binarypack::binarypack::Unpacked>(data);
+    if let Ok(stats) = validate(data) {
+        assert!(Unpacker::new(data).unpack().is_ok());
+        assert!(stats.size <= data.len());
+    }
+
+    let options = UnpackOptions {
+        max_depth: usize::MAX,
+        max_elements: Some(1 << 16),
+        max_string_len: Some(1 << 16),
+        max_total_bytes: Some(1 << 20),
+        max_map_len: Some(1 << 12),
+        strict: true,
+        invalid_utf8: InvalidUtf8::Replace,
+        js_numbers: true,
+    };
+    let _ = unpack_with_options(data, &options);
+});
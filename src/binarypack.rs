@@ -1,11 +1,14 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
-use std::mem::size_of;
+use std::io::Write;
+use std::mem::{self, size_of};
+use std::str;
 
 use byteorder::{BigEndian, ByteOrder};
 use num::{NumCast, Unsigned};
 
-use crate::error::{Error, Result};
+use crate::error::{Error, ErrorKind, PathSegment, Result, Warning, WarningKind};
 
 /// An enum representing possible unpacked structures
 #[derive(Clone, Debug)]
@@ -30,6 +33,7 @@ pub enum Unpacked {
 }
 
 impl PartialEq for Unpacked {
+    #[allow(clippy::needless_return)]
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
             (Unpacked::Bool(a), Unpacked::Bool(b)) => a == b,
@@ -84,12 +88,70 @@ impl PartialEq for Unpacked {
 impl Eq for Unpacked {}
 
 impl Hash for Unpacked {
+    #[allow(unused_must_use)]
     fn hash<H: Hasher>(&self, state: &mut H) {
         state.write(format!("{:?}", self).as_bytes());
         state.finish();
     }
 }
 
+macro_rules! impl_from {
+    ($($t:ty => $variant:ident),*) => {
+        $(
+            impl From<$t> for Unpacked {
+                fn from(v: $t) -> Self {
+                    Unpacked::$variant(v)
+                }
+            }
+        )*
+    };
+}
+
+impl_from!(
+    u8 => Uint8,
+    u16 => Uint16,
+    u32 => Uint32,
+    u64 => Uint64,
+    i8 => Int8,
+    i16 => Int16,
+    i32 => Int32,
+    i64 => Int64,
+    f32 => Float,
+    f64 => Double,
+    bool => Bool,
+    Vec<u8> => Raw,
+    String => String,
+    Vec<Unpacked> => Array,
+    HashMap<Unpacked, Unpacked> => Map
+);
+
+impl From<&str> for Unpacked {
+    fn from(s: &str) -> Self {
+        Unpacked::String(s.to_string())
+    }
+}
+
+impl From<&[u8]> for Unpacked {
+    fn from(bytes: &[u8]) -> Self {
+        Unpacked::Raw(bytes.to_vec())
+    }
+}
+
+impl<const N: usize> From<&[u8; N]> for Unpacked {
+    fn from(bytes: &[u8; N]) -> Self {
+        Unpacked::Raw(bytes.to_vec())
+    }
+}
+
+impl<T: Into<Unpacked>> From<Option<T>> for Unpacked {
+    fn from(v: Option<T>) -> Self {
+        match v {
+            Some(v) => v.into(),
+            None => Unpacked::Null,
+        }
+    }
+}
+
 const MAP_MASK: u8 = 0x80;
 const ARR_MASK: u8 = 0x90;
 const RAW_MASK: u8 = 0xa0;
@@ -119,197 +181,969 @@ const PACKED_ARR_U32: u8 = 0xdd;
 const PACKED_MAP_U16: u8 = 0xde;
 const PACKED_MAP_U32: u8 = 0xdf;
 
+/// The largest integer that a JavaScript number, an IEEE 754 double, holds exactly along with
+/// every integer below it
+const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+/// Returns true for the type bytes that js-binarypack leaves unassigned
+pub(crate) fn is_reserved(type_: u8) -> bool {
+    matches!(type_, 0xc4..=0xc9 | 0xd4..=0xd7)
+}
+
+/// A borrowed counterpart of `Unpacked`
+///
+/// Strings and raw bytes are slices of the buffer being unpacked rather than copies of it, so
+/// large payloads can be inspected without duplicating them in memory. The only exception is a
+/// string with invalid UTF-8 that was repaired as set by `UnpackOptions::invalid_utf8`. Map
+/// entries are kept in the order they appear in the input.
+#[derive(Clone, Debug, PartialEq)]
+pub enum UnpackedRef<'a> {
+    Uint8(u8),
+    Uint16(u16),
+    Uint32(u32),
+    Uint64(u64),
+    Int8(i8),
+    Int16(i16),
+    Int32(i32),
+    Int64(i64),
+    Float(f32),
+    Double(f64),
+    Bool(bool),
+    Raw(&'a [u8]),
+    String(Cow<'a, str>),
+    Null,
+    Undefined,
+    Array(Vec<UnpackedRef<'a>>),
+    Map(Vec<(UnpackedRef<'a>, UnpackedRef<'a>)>),
+}
+
+impl<'a> UnpackedRef<'a> {
+    /// Copies the value into an owned `Unpacked`
+    pub fn into_owned(self) -> Unpacked {
+        match self {
+            UnpackedRef::Uint8(a) => Unpacked::Uint8(a),
+            UnpackedRef::Uint16(a) => Unpacked::Uint16(a),
+            UnpackedRef::Uint32(a) => Unpacked::Uint32(a),
+            UnpackedRef::Uint64(a) => Unpacked::Uint64(a),
+            UnpackedRef::Int8(a) => Unpacked::Int8(a),
+            UnpackedRef::Int16(a) => Unpacked::Int16(a),
+            UnpackedRef::Int32(a) => Unpacked::Int32(a),
+            UnpackedRef::Int64(a) => Unpacked::Int64(a),
+            UnpackedRef::Float(f) => Unpacked::Float(f),
+            UnpackedRef::Double(f) => Unpacked::Double(f),
+            UnpackedRef::Bool(b) => Unpacked::Bool(b),
+            UnpackedRef::Raw(bytes) => Unpacked::Raw(bytes.to_vec()),
+            UnpackedRef::String(s) => Unpacked::String(s.into_owned()),
+            UnpackedRef::Null => Unpacked::Null,
+            UnpackedRef::Undefined => Unpacked::Undefined,
+            UnpackedRef::Array(v) => {
+                Unpacked::Array(v.into_iter().map(UnpackedRef::into_owned).collect())
+            }
+            UnpackedRef::Map(m) => Unpacked::Map(
+                m.into_iter()
+                    .map(|(k, v)| (k.into_owned(), v.into_owned()))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Reads any numeric variant as an `f64`, rounding 64 bit integers beyond ±(2^53 - 1) to
+    /// the nearest double. Returns `None` for other variants.
+    pub fn as_f64(&self) -> Option<f64> {
+        Some(match *self {
+            UnpackedRef::Uint8(a) => a.into(),
+            UnpackedRef::Uint16(a) => a.into(),
+            UnpackedRef::Uint32(a) => a.into(),
+            UnpackedRef::Uint64(a) => a as f64,
+            UnpackedRef::Int8(a) => a.into(),
+            UnpackedRef::Int16(a) => a.into(),
+            UnpackedRef::Int32(a) => a.into(),
+            UnpackedRef::Int64(a) => a as f64,
+            UnpackedRef::Float(f) => f.into(),
+            UnpackedRef::Double(f) => f,
+            _ => return None,
+        })
+    }
+}
+
+impl<'a> From<UnpackedRef<'a>> for Unpacked {
+    fn from(value: UnpackedRef<'a>) -> Self {
+        value.into_owned()
+    }
+}
+
+/// A single item of the wire format: either a complete scalar value or the header of a
+/// container whose elements follow it
+#[derive(Debug)]
+pub(crate) enum Token<'a> {
+    Value(UnpackedRef<'a>),
+    Array(usize),
+    Map(usize),
+    /// A MessagePack extension value of the type given, which is read as `Undefined`
+    Extension(i8),
+}
+
+/// A value that containers can be built from, either `Unpacked` or `UnpackedRef`
+pub(crate) trait Element: Sized {
+    type Entries;
+
+    fn array(items: Vec<Self>) -> Self;
+    fn new_map() -> Self::Entries;
+    fn insert(map: &mut Self::Entries, key: Self, value: Self);
+    fn map(map: Self::Entries) -> Self;
+    /// The key as it is recorded in the path of an error
+    fn to_key(&self) -> Unpacked;
+}
+
+impl Element for Unpacked {
+    type Entries = HashMap<Unpacked, Unpacked>;
+
+    fn array(items: Vec<Self>) -> Self {
+        Unpacked::Array(items)
+    }
+
+    fn new_map() -> Self::Entries {
+        HashMap::new()
+    }
+
+    fn insert(map: &mut Self::Entries, key: Self, value: Self) {
+        map.insert(key, value);
+    }
+
+    fn map(map: Self::Entries) -> Self {
+        Unpacked::Map(map)
+    }
+
+    fn to_key(&self) -> Unpacked {
+        self.clone()
+    }
+}
+
+impl<'a> Element for UnpackedRef<'a> {
+    type Entries = Vec<(UnpackedRef<'a>, UnpackedRef<'a>)>;
+
+    fn array(items: Vec<Self>) -> Self {
+        UnpackedRef::Array(items)
+    }
+
+    fn new_map() -> Self::Entries {
+        vec![]
+    }
+
+    fn insert(map: &mut Self::Entries, key: Self, value: Self) {
+        map.push((key, value));
+    }
+
+    fn map(map: Self::Entries) -> Self {
+        UnpackedRef::Map(map)
+    }
+
+    fn to_key(&self) -> Unpacked {
+        self.clone().into_owned()
+    }
+}
+
+/// A container that has been opened but not yet filled
+pub(crate) enum Frame<V: Element> {
+    Array {
+        items: Vec<V>,
+        size: usize,
+    },
+    Map {
+        map: V::Entries,
+        key: Option<V>,
+        // The number of entries read, which can differ from the size of `map` if keys repeat
+        len: usize,
+        size: usize,
+    },
+}
+
+impl<V: Element> Frame<V> {
+    /// Opens a container for a header token, or returns a scalar or empty container as a value
+    pub(crate) fn new<'a>(token: Token<'a>) -> std::result::Result<Frame<V>, V>
+    where
+        V: From<UnpackedRef<'a>>,
+    {
+        match token {
+            Token::Value(value) => Err(V::from(value)),
+            Token::Extension(_) => Err(V::from(UnpackedRef::Undefined)),
+            Token::Array(0) => Err(V::array(vec![])),
+            Token::Map(0) => Err(V::map(V::new_map())),
+            Token::Array(size) => Ok(Frame::Array {
+                items: vec![],
+                size,
+            }),
+            Token::Map(size) => Ok(Frame::Map {
+                map: V::new_map(),
+                key: None,
+                len: 0,
+                size,
+            }),
+        }
+    }
+
+    /// Adds an element, returning true once the container holds all of its elements
+    pub(crate) fn push(&mut self, value: V) -> bool {
+        match self {
+            Frame::Array { items, size } => {
+                items.push(value);
+                items.len() == *size
+            }
+            Frame::Map {
+                map,
+                key,
+                len,
+                size,
+            } => match key.take() {
+                None => {
+                    *key = Some(value);
+                    false
+                }
+                Some(k) => {
+                    V::insert(map, k, value);
+                    *len += 1;
+                    *len == *size
+                }
+            },
+        }
+    }
+
+    /// Returns true if the next element is the key of a map entry
+    pub(crate) fn expects_key(&self) -> bool {
+        matches!(self, Frame::Map { key: None, .. })
+    }
+
+    /// The position within this container of the element being decoded
+    pub(crate) fn segment(&self) -> PathSegment {
+        match self {
+            Frame::Array { items, .. } => PathSegment::Index(items.len()),
+            Frame::Map { key, len, .. } => match key {
+                Some(k) => PathSegment::Key(k.to_key()),
+                None => PathSegment::MapKey(*len),
+            },
+        }
+    }
+
+    pub(crate) fn finish(self) -> V {
+        match self {
+            Frame::Array { items, .. } => V::array(items),
+            Frame::Map { map, .. } => V::map(map),
+        }
+    }
+
+    /// Adds the path through the open containers in `stack` to an error
+    pub(crate) fn in_context(stack: &[Frame<V>], e: Error) -> Error {
+        e.within(stack.iter().map(Frame::segment))
+    }
+}
+
+/// What to do with a string that is not valid UTF-8
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InvalidUtf8 {
+    /// Fail with `ErrorKind::StringParseError`
+    Error,
+    /// Replace invalid sequences with U+FFFD REPLACEMENT CHARACTER
+    Replace,
+    /// Return the bytes of the string as `Raw`
+    Raw,
+}
+
+/// Limits and behaviour that apply while unpacking
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnpackOptions {
+    /// The deepest that arrays and maps may be nested. A top level scalar has a depth of 0, and
+    /// each array or map around it adds 1.
+    ///
+    /// Unpacking does not recurse, but comparing, hashing, packing and dropping an `Unpacked` do,
+    /// so this also bounds the stack those need for values read from untrusted input.
+    pub max_depth: usize,
+    /// The most values, counting every array and map as well as each of their elements, keys
+    /// and values, that a single top level value may contain
+    pub max_elements: Option<usize>,
+    /// The longest a single `String` or `Raw` may be, in bytes
+    pub max_string_len: Option<usize>,
+    /// The most bytes of input a single top level value may take up
+    pub max_total_bytes: Option<usize>,
+    /// The most entries a single `Map` may have
+    pub max_map_len: Option<usize>,
+    /// Rejects input that js-binarypack would never have written: reserved type bytes, which are
+    /// otherwise read as `Undefined`, map keys that are not strings, and lengths and integers
+    /// written in a wider form than needed. `unpack_with_options` also rejects bytes following
+    /// the value.
+    ///
+    /// An integer must not fit the next narrower form of the same signedness, and a length must
+    /// not fit the next narrower form, so values should be packed with `PackOptions::compact`
+    /// and `narrow_integers`. `pack` keeps the width of every integer and writes lengths below
+    /// 16 in the 16 bit form, which strict mode rejects.
+    pub strict: bool,
+    /// How strings that are not valid UTF-8 are handled. Unless this is `InvalidUtf8::Error`,
+    /// every string that is repaired is recorded as a `Warning`.
+    pub invalid_utf8: InvalidUtf8,
+    /// Reads every number as a `Double`, whatever form it was packed in, as a JavaScript peer
+    /// would. An integer beyond ±(2^53 - 1) is converted to the nearest double and recorded as a
+    /// `Warning`, which says whether it had to be rounded. With serde, fields must then accept `f64`.
+    pub js_numbers: bool,
+}
+
+impl Default for UnpackOptions {
+    /// Limits nesting to 128 levels, and places no limit on sizes
+    fn default() -> Self {
+        UnpackOptions {
+            max_depth: 128,
+            max_elements: None,
+            max_string_len: None,
+            max_total_bytes: None,
+            max_map_len: None,
+            strict: false,
+            invalid_utf8: InvalidUtf8::Error,
+            js_numbers: false,
+        }
+    }
+}
+
+/// Choices that apply while packing
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PackOptions {
+    /// Writes the entries of every map sorted by the bytes of their packed keys, so that equal
+    /// values always pack to the same bytes. Entries whose keys pack identically, such as two
+    /// `Undefined` keys, are sorted by the bytes of their packed values.
+    pub canonical: bool,
+    /// Writes lengths below 16 in the one byte fixraw, fixstr, fixarray and fixmap forms, as
+    /// js-binarypack does, instead of with a 16 bit length
+    pub compact: bool,
+    /// Writes every integer in the narrowest form that holds its value, trying the forms in the
+    /// same order as js-binarypack's `pack_integer`, so `Uint32(3)` takes one byte rather than
+    /// five. The value then unpacks as the variant of that form, e.g. `Uint8(3)`. Together with
+    /// `compact`, this packs values byte for byte as js-binarypack does.
+    pub narrow_integers: bool,
+    /// Packs numbers as a JavaScript peer can read them. Integers are narrowed as with
+    /// `narrow_integers`, but fail with `ErrorKind::UnsafeInteger` beyond ±(2^53 - 1), where a
+    /// JavaScript number would round them. A `Float` or `Double` with an integral value that an
+    /// `Int64` or `Uint64` holds, including -0.0, is packed as an integer as js-binarypack does,
+    /// and any other is packed as a `Double`.
+    pub js_numbers: bool,
+}
+
+/// The wire format an `Unpacker` reads
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Format {
+    Binarypack,
+    MessagePack,
+    Cbor,
+}
+
+/// How much of its budget the top level value being unpacked has used so far
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Usage {
+    /// The number of values decoded, plus the elements announced by open containers that have
+    /// not been decoded yet
+    elements: usize,
+    /// The number of bytes of input consumed
+    bytes: usize,
+}
+
 /// An object that can unpack the js-binarypack format
+///
+/// A buffer may hold several values back to back. Each call to `unpack` (or each step of the
+/// `Iterator` implementation) decodes the next one, and `offset`/`remaining` report how much of
+/// the buffer has been consumed so far.
+///
+/// `msgpack::unpacker` and `cbor::unpacker` create ones that read standard MessagePack and CBOR
+/// instead.
 pub struct Unpacker<'a> {
+    input: &'a [u8],
     data: &'a [u8],
+    failed: bool,
+    options: UnpackOptions,
+    format: Format,
+    depth: usize,
+    usage: Usage,
+    token_start: usize,
+    // Whether the next token is the key of a map entry
+    key: bool,
+    warnings: Vec<Warning>,
 }
 
 impl<'a> Unpacker<'a> {
-    fn new(data: &[u8]) -> Unpacker {
-        Unpacker { data }
+    /// Creates an unpacker that reads values from the start of `data`
+    pub fn new(data: &'a [u8]) -> Unpacker<'a> {
+        Unpacker::with_options(data, UnpackOptions::default())
+    }
+
+    /// Creates an unpacker that reads values from the start of `data` using `options`
+    pub fn with_options(data: &'a [u8], options: UnpackOptions) -> Unpacker<'a> {
+        Unpacker::nested(data, options, 0, Usage::default())
+    }
+
+    /// Creates an unpacker for input that sits `depth` containers deep in a larger value, which
+    /// has already used `usage` of its budget
+    pub(crate) fn nested(
+        data: &'a [u8],
+        options: UnpackOptions,
+        depth: usize,
+        usage: Usage,
+    ) -> Unpacker<'a> {
+        Unpacker {
+            input: data,
+            data,
+            failed: false,
+            options,
+            format: Format::Binarypack,
+            depth,
+            usage,
+            token_start: 0,
+            key: false,
+            warnings: vec![],
+        }
+    }
+
+    /// Switches the unpacker to reading `format`
+    pub(crate) fn with_format(mut self, format: Format) -> Unpacker<'a> {
+        self.format = format;
+        self
+    }
+
+    pub(crate) fn options(&self) -> &UnpackOptions {
+        &self.options
+    }
+
+    /// Records a problem with the token being unpacked
+    pub(crate) fn warn(&mut self, kind: WarningKind) {
+        self.warnings.push(Warning::new(kind, self.token_start));
+    }
+
+    /// The problems repaired, and the integers rounded, in the values unpacked so far
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// Returns the warnings recorded so far, and clears them
+    pub fn take_warnings(&mut self) -> Vec<Warning> {
+        mem::take(&mut self.warnings)
+    }
+
+    /// How much of its budget the value being unpacked has used
+    pub(crate) fn usage(&self) -> Usage {
+        self.usage
+    }
+
+    /// Moves one level deeper, into the contents of a container
+    pub(crate) fn enter(&mut self) {
+        self.depth += 1;
+    }
+
+    /// Moves back out of a container entered with `enter`
+    pub(crate) fn leave(&mut self) {
+        self.depth -= 1;
+    }
+
+    /// The number of bytes of the input consumed by the values unpacked so far
+    pub fn offset(&self) -> usize {
+        self.input.len() - self.data.len()
+    }
+
+    /// The bytes of the input that have not been unpacked yet
+    pub fn remaining(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Returns true if every byte of the input has been consumed
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Consumes the next value if it is `Null` or `Undefined`, returning whether it did
+    #[cfg(feature = "serde")]
+    pub(crate) fn unpack_nil(&mut self) -> Result<bool> {
+        match self.data.first() {
+            Some(&PACKED_NULL) | Some(&PACKED_UNDEFINED) => {
+                self.unpack_token()?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
     }
 
     fn unpack_unsigned<T: Copy + Unsigned + NumCast>(&mut self) -> Result<T> {
         let length = size_of::<T>();
         if self.data.len() < length {
-            return Err(Error::EndOfData);
+            return Err(Error::end_of_data(length, self.data.len()));
         }
 
-        let mut digits = vec![];
-        for i in 0..length {
-            digits.push(T::from(self.data[i]).unwrap());
-        }
-        self.data = &self.data[length..];
+        let (digits, rest) = self.data.split_at(length);
+        self.data = rest;
 
-        let mut val: T = T::zero();
         // If the cast of 256 fails, then T must be u8, so we know there's only one digit to
         // worry about.
         let shift = T::from(256).unwrap_or(T::zero());
-        for d in digits {
-            val = (val * shift) + d;
-        }
-
-        Ok(val)
+        Ok(digits.iter().fold(T::zero(), |val, &d| {
+            (val * shift) + T::from(d).unwrap_or(T::zero())
+        }))
     }
 
-    fn unpack_uint8(&mut self) -> Result<u8> {
+    pub(crate) fn unpack_uint8(&mut self) -> Result<u8> {
         self.unpack_unsigned()
     }
 
-    fn unpack_int8(&mut self) -> Result<i8> {
+    pub(crate) fn unpack_int8(&mut self) -> Result<i8> {
         self.unpack_unsigned().map(|x: u8| x as i8)
     }
 
-    fn unpack_uint16(&mut self) -> Result<u16> {
+    pub(crate) fn unpack_uint16(&mut self) -> Result<u16> {
         self.unpack_unsigned()
     }
 
-    fn unpack_int16(&mut self) -> Result<i16> {
+    pub(crate) fn unpack_int16(&mut self) -> Result<i16> {
         self.unpack_unsigned().map(|x: u16| x as i16)
     }
 
-    fn unpack_uint32(&mut self) -> Result<u32> {
+    pub(crate) fn unpack_uint32(&mut self) -> Result<u32> {
         self.unpack_unsigned()
     }
 
-    fn unpack_int32(&mut self) -> Result<i32> {
+    pub(crate) fn unpack_int32(&mut self) -> Result<i32> {
         self.unpack_unsigned().map(|x: u32| x as i32)
     }
 
-    fn unpack_uint64(&mut self) -> Result<u64> {
+    pub(crate) fn unpack_uint64(&mut self) -> Result<u64> {
         self.unpack_unsigned()
     }
 
-    fn unpack_int64(&mut self) -> Result<i64> {
+    pub(crate) fn unpack_int64(&mut self) -> Result<i64> {
         self.unpack_unsigned().map(|x: u64| x as i64)
     }
 
-    fn unpack_raw(&mut self, size: usize) -> Result<Vec<u8>> {
-        let mut raw = vec![];
+    pub(crate) fn unpack_raw(&mut self, size: usize) -> Result<&'a [u8]> {
+        if let Some(limit) = self.options.max_string_len {
+            if size > limit {
+                return Err(ErrorKind::StringLengthLimitExceeded(limit).into());
+            }
+        }
+        if let Some(limit) = self.options.max_total_bytes {
+            let bytes = self.usage.bytes + (self.offset() - self.token_start);
+            if size > limit.saturating_sub(bytes) {
+                return Err(ErrorKind::ByteLimitExceeded(limit).into());
+            }
+        }
         if self.data.len() < size {
-            return Err(Error::EndOfData);
+            return Err(Error::end_of_data(size, self.data.len()));
         }
 
-        for i in 0..size {
-            raw.push(self.data[i]);
-        }
-        self.data = &self.data[size..];
+        let (raw, rest) = self.data.split_at(size);
+        self.data = rest;
 
         Ok(raw)
     }
 
-    fn unpack_string(&mut self, size: usize) -> Result<String> {
-        Ok(String::from_utf8(self.unpack_raw(size)?)?)
+    pub(crate) fn unpack_string(&mut self, size: usize) -> Result<UnpackedRef<'a>> {
+        let bytes = self.unpack_raw(size)?;
+        let e = match str::from_utf8(bytes) {
+            Ok(s) => return Ok(UnpackedRef::String(Cow::Borrowed(s))),
+            Err(e) => e,
+        };
+
+        let (value, kind) = match self.options.invalid_utf8 {
+            InvalidUtf8::Error => return Err(e.into()),
+            InvalidUtf8::Replace => (
+                UnpackedRef::String(String::from_utf8_lossy(bytes)),
+                WarningKind::ReplacedInvalidUtf8,
+            ),
+            InvalidUtf8::Raw => (UnpackedRef::Raw(bytes), WarningKind::InvalidUtf8AsRaw),
+        };
+        self.warn(kind);
+        Ok(value)
     }
 
-    fn unpack_array(&mut self, size: usize) -> Result<Vec<Unpacked>> {
-        let mut arr = vec![];
-        for _i in 0..size {
-            arr.push(self.unpack()?);
-        }
-
-        Ok(arr)
-    }
-
-    fn unpack_map(&mut self, size: usize) -> Result<HashMap<Unpacked, Unpacked>> {
-        let mut map = HashMap::new();
-        for _i in 0..size {
-            map.insert(self.unpack()?, self.unpack()?);
-        }
-
-        Ok(map)
-    }
-
-    fn unpack_float(&mut self) -> Result<f32> {
+    #[allow(clippy::unnecessary_mut_passed)]
+    pub(crate) fn unpack_float(&mut self) -> Result<f32> {
         let i = self.unpack_uint32()?;
         let mut bytes = [0u8; 4];
         BigEndian::write_u32(&mut bytes, i);
         Ok(BigEndian::read_f32(&mut bytes))
     }
 
-    fn unpack_double(&mut self) -> Result<f64> {
+    #[allow(clippy::unnecessary_mut_passed)]
+    pub(crate) fn unpack_double(&mut self) -> Result<f64> {
         let i = self.unpack_uint64()?;
         let mut bytes = [0u8; 8];
         BigEndian::write_u64(&mut bytes, i);
         Ok(BigEndian::read_f64(&mut bytes))
     }
 
-    fn unpack(&mut self) -> Result<Unpacked> {
-        let type_ = self.unpack_uint8()?;
+    /// Unpacks the next value from the input
+    ///
+    /// On failure the unpacker is left positioned at the start of the value that could not be
+    /// decoded, so `offset` and `remaining` point at the offending bytes.
+    pub fn unpack(&mut self) -> Result<Unpacked> {
+        self.usage = Usage::default();
+        let start = self.data;
+        let warnings = self.warnings.len();
+        let result = self.unpack_tree();
+        if result.is_err() {
+            self.data = start;
+            self.warnings.truncate(warnings);
+        }
+        result
+    }
+
+    /// Unpacks the next value without copying strings or raw bytes out of the input
+    ///
+    /// Like `unpack`, the unpacker is left at the start of the value if it cannot be decoded.
+    pub fn unpack_ref(&mut self) -> Result<UnpackedRef<'a>> {
+        self.usage = Usage::default();
+        let start = self.data;
+        let warnings = self.warnings.len();
+        let result = self.unpack_tree();
+        if result.is_err() {
+            self.data = start;
+            self.warnings.truncate(warnings);
+        }
+        result
+    }
+
+    /// Unpacks a whole value, keeping the containers it is inside of on an explicit stack rather
+    /// than recursing into them
+    fn unpack_tree<V: Element + From<UnpackedRef<'a>>>(&mut self) -> Result<V> {
+        let depth = self.depth;
+        let mut stack = vec![];
+        let result = self.unpack_frames(&mut stack);
+        self.depth = depth;
+        result.map_err(|e| Frame::in_context(&stack, e))
+    }
+
+    fn unpack_frames<V>(&mut self, stack: &mut Vec<Frame<V>>) -> Result<V>
+    where
+        V: Element + From<UnpackedRef<'a>>,
+    {
+        'tokens: loop {
+            if stack.last().is_some_and(Frame::expects_key) {
+                self.expect_key();
+            }
+            let mut value = match Frame::new(self.unpack_token()?) {
+                Ok(frame) => {
+                    stack.push(frame);
+                    self.enter();
+                    continue;
+                }
+                Err(value) => value,
+            };
+
+            while let Some(mut frame) = stack.pop() {
+                if !frame.push(value) {
+                    stack.push(frame);
+                    continue 'tokens;
+                }
+                self.leave();
+                value = frame.finish();
+            }
+            return Ok(value);
+        }
+    }
+
+    /// Reads the next value a token at a time without building it, passing each token to `visit`
+    /// along with the number of containers around it
+    ///
+    /// Errors returned by `visit` record the offset and type byte of the token. Like `unpack`,
+    /// the unpacker is left at the start of the value if the walk fails.
+    pub(crate) fn walk<F>(&mut self, mut visit: F) -> Result<()>
+    where
+        F: FnMut(&mut Unpacker<'a>, Token<'a>, usize) -> Result<()>,
+    {
+        self.usage = Usage::default();
+        let start = self.data;
+        let warnings = self.warnings.len();
+        let depth = self.depth;
+        // The elements left to read in each open container, and whether it is a map
+        let mut open: Vec<(usize, bool)> = vec![];
+        let result = loop {
+            if let Some(&(left, true)) = open.last() {
+                if left % 2 == 0 {
+                    self.expect_key();
+                }
+            }
+            let token = match self.unpack_token() {
+                Ok(token) => token,
+                Err(e) => break Err(e),
+            };
+            let header = match token {
+                Token::Value(_) | Token::Extension(_) => None,
+                Token::Array(size) => Some((size, false)),
+                Token::Map(size) => Some((size.saturating_mul(2), true)),
+            };
+            let (start, type_) = (self.token_start, self.token_type());
+            if let Err(e) = visit(self, token, open.len()) {
+                break Err(e.at(start, Some(type_)));
+            }
+
+            if let Some((left, _)) = open.last_mut() {
+                *left -= 1;
+            }
+            if let Some((size, is_map)) = header.filter(|&(size, _)| size > 0) {
+                open.push((size, is_map));
+                self.enter();
+            }
+            while let Some(&(0, _)) = open.last() {
+                open.pop();
+                self.leave();
+            }
+            if open.is_empty() {
+                break Ok(());
+            }
+        };
+        self.depth = depth;
+        if result.is_err() {
+            self.data = start;
+            self.warnings.truncate(warnings);
+        }
+        result
+    }
+
+    /// The type byte of the token unpacked last
+    pub(crate) fn token_type(&self) -> u8 {
+        self.input[self.token_start]
+    }
+
+    /// Unpacks a single scalar value, or the header of an array or map without its contents
+    ///
+    /// Errors record the offset and type byte of the value. Every token is checked against the
+    /// limits in the options, and a container is checked against them using the length in its
+    /// header, before any of its elements are read.
+    pub(crate) fn unpack_token(&mut self) -> Result<Token<'a>> {
+        let start = self.offset();
+        let key = mem::replace(&mut self.key, false);
+        self.token_start = start;
+        let type_ = self.unpack_uint8().map_err(|e| e.at(start, None))?;
+        let token = match self.format {
+            Format::Binarypack => self.unpack_token_body(type_),
+            Format::MessagePack => self.unpack_msgpack_token_body(type_),
+            Format::Cbor => self.unpack_cbor_token_body(type_),
+        };
+        let token = token
+            .and_then(|token| self.check_token(token, start, key))
+            .map_err(|e| e.at(start, Some(type_)))?;
+        let token = match token {
+            Token::Value(value) if self.options.js_numbers => Token::Value(self.js_number(value)),
+            token => token,
+        };
+
+        self.usage.bytes += self.offset() - start;
+        Ok(token)
+    }
+
+    /// Marks the next token as the key of a map entry, which strict mode requires to be a string
+    pub(crate) fn expect_key(&mut self) {
+        self.key = true;
+    }
+
+    /// Fails in strict mode if any input follows the values unpacked so far
+    pub(crate) fn check_end(&self) -> Result<()> {
+        if self.options.strict && !self.data.is_empty() {
+            return Err(Error::new(ErrorKind::TrailingData).at(self.offset(), None));
+        }
+        Ok(())
+    }
+
+    /// Fails in strict mode if a value was written in a wider form than needed
+    pub(crate) fn check_minimal(&self, minimal: bool) -> Result<()> {
+        if self.options.strict && !minimal {
+            return Err(ErrorKind::NonMinimalEncoding.into());
+        }
+        Ok(())
+    }
+
+    /// Checks a token against the limits in the options, and against strict mode
+    fn check_token(&mut self, token: Token<'a>, start: usize, key: bool) -> Result<Token<'a>> {
+        let options = &self.options;
+        if options.strict && key && !matches!(token, Token::Value(UnpackedRef::String(_))) {
+            return Err(ErrorKind::NonStringKey.into());
+        }
+
+        let announced = match token {
+            Token::Value(_) | Token::Extension(_) => 0,
+            Token::Array(size) => size,
+            Token::Map(size) => {
+                if let Some(limit) = options.max_map_len {
+                    if size > limit {
+                        return Err(ErrorKind::MapLengthLimitExceeded(limit).into());
+                    }
+                }
+                size.saturating_mul(2)
+            }
+        };
+
+        if let Token::Array(_) | Token::Map(_) = token {
+            if self.depth >= options.max_depth {
+                return Err(ErrorKind::DepthLimitExceeded(options.max_depth).into());
+            }
+        }
+
+        // Values inside a container were already counted when its header was read
+        let elements = if self.depth == 0 { 1 } else { 0 };
+        let elements = self.usage.elements.saturating_add(elements + announced);
+        if let Some(limit) = options.max_elements {
+            if elements > limit {
+                return Err(ErrorKind::ElementLimitExceeded(limit).into());
+            }
+        }
+        if let Some(limit) = options.max_total_bytes {
+            if self.usage.bytes + (self.offset() - start) > limit {
+                return Err(ErrorKind::ByteLimitExceeded(limit).into());
+            }
+        }
+
+        self.usage.elements = elements;
+        Ok(token)
+    }
+
+    fn unpack_token_body(&mut self, type_: u8) -> Result<Token<'a>> {
         if type_ < MAP_MASK {
-            return Ok(Unpacked::Uint8(type_));
+            return Ok(Token::Value(UnpackedRef::Uint8(type_)));
         } else if (type_ ^ INT_MASK) < 0x20 {
-            return Ok(Unpacked::Int8((type_ ^ INT_MASK) as i8 - 0x20));
+            return Ok(Token::Value(UnpackedRef::Int8(
+                (type_ ^ INT_MASK) as i8 - 0x20,
+            )));
         }
 
         let size = type_ ^ MAP_MASK;
         if size <= 0x0f {
-            return Ok(Unpacked::Map(self.unpack_map(size as usize)?));
+            return Ok(Token::Map(size as usize));
         }
 
         let size = type_ ^ ARR_MASK;
         if size <= 0x0f {
-            return Ok(Unpacked::Array(self.unpack_array(size as usize)?));
+            return Ok(Token::Array(size as usize));
         }
 
         let size = type_ ^ RAW_MASK;
         if size <= 0x0f {
-            return Ok(Unpacked::Raw(self.unpack_raw(size as usize)?));
+            return Ok(Token::Value(UnpackedRef::Raw(
+                self.unpack_raw(size as usize)?,
+            )));
         }
         let size = type_ ^ STR_MASK;
         if size <= 0x0f {
-            return Ok(Unpacked::String(self.unpack_string(size as usize)?));
-        }
-
-        Ok(match type_ {
-            PACKED_NULL => Unpacked::Null,
-            PACKED_FALSE => Unpacked::Bool(false),
-            PACKED_TRUE => Unpacked::Bool(true),
-            PACKED_FLOAT => Unpacked::Float(self.unpack_float()?),
-            PACKED_DOUBLE => Unpacked::Double(self.unpack_double()?),
-            PACKED_UINT8 => Unpacked::Uint8(self.unpack_uint8()?),
-            PACKED_UINT16 => Unpacked::Uint16(self.unpack_uint16()?),
-            PACKED_UINT32 => Unpacked::Uint32(self.unpack_uint32()?),
-            PACKED_UINT64 => Unpacked::Uint64(self.unpack_uint64()?),
-            PACKED_INT8 => Unpacked::Int8(self.unpack_int8()?),
-            PACKED_INT16 => Unpacked::Int16(self.unpack_int16()?),
-            PACKED_INT32 => Unpacked::Int32(self.unpack_int32()?),
-            PACKED_INT64 => Unpacked::Int64(self.unpack_int64()?),
-            PACKED_STR_U16 => {
-                let size = self.unpack_uint16()? as usize;
-                Unpacked::String(self.unpack_string(size)?)
+            return Ok(Token::Value(self.unpack_string(size as usize)?));
+        }
+
+        Ok(Token::Value(match type_ {
+            PACKED_NULL => UnpackedRef::Null,
+            PACKED_FALSE => UnpackedRef::Bool(false),
+            PACKED_TRUE => UnpackedRef::Bool(true),
+            PACKED_FLOAT => UnpackedRef::Float(self.unpack_float()?),
+            PACKED_DOUBLE => UnpackedRef::Double(self.unpack_double()?),
+            PACKED_UNDEFINED => UnpackedRef::Undefined,
+            PACKED_UINT8 => {
+                let a = self.unpack_uint8()?;
+                self.check_minimal(a >= MAP_MASK)?;
+                UnpackedRef::Uint8(a)
             }
-            PACKED_STR_U32 => {
-                let size = self.unpack_uint32()? as usize;
-                Unpacked::String(self.unpack_string(size)?)
+            PACKED_UINT16 => {
+                let a = self.unpack_uint16()?;
+                self.check_minimal(a > u8::MAX.into())?;
+                UnpackedRef::Uint16(a)
             }
-            PACKED_RAW_U16 => {
-                let size = self.unpack_uint16()? as usize;
-                Unpacked::Raw(self.unpack_raw(size)?)
+            PACKED_UINT32 => {
+                let a = self.unpack_uint32()?;
+                self.check_minimal(a > u16::MAX.into())?;
+                UnpackedRef::Uint32(a)
             }
-            PACKED_RAW_U32 => {
-                let size = self.unpack_uint32()? as usize;
-                Unpacked::Raw(self.unpack_raw(size)?)
+            PACKED_UINT64 => {
+                let a = self.unpack_uint64()?;
+                self.check_minimal(a > u32::MAX.into())?;
+                UnpackedRef::Uint64(a)
             }
-            PACKED_ARR_U16 => {
-                let size = self.unpack_uint16()? as usize;
-                Unpacked::Array(self.unpack_array(size)?)
+            PACKED_INT8 => {
+                let a = self.unpack_int8()?;
+                self.check_minimal(!(a < 0 && a > -0x20))?;
+                UnpackedRef::Int8(a)
             }
-            PACKED_ARR_U32 => {
-                let size = self.unpack_uint32()? as usize;
-                Unpacked::Array(self.unpack_array(size)?)
+            PACKED_INT16 => {
+                let a = self.unpack_int16()?;
+                self.check_minimal(!(-0x80..=0x7f).contains(&a))?;
+                UnpackedRef::Int16(a)
             }
-            PACKED_MAP_U16 => {
-                let size = self.unpack_uint16()? as usize;
-                Unpacked::Map(self.unpack_map(size)?)
+            PACKED_INT32 => {
+                let a = self.unpack_int32()?;
+                self.check_minimal(!(-0x8000..=0x7fff).contains(&a))?;
+                UnpackedRef::Int32(a)
             }
-            PACKED_MAP_U32 => {
-                let size = self.unpack_uint32()? as usize;
-                Unpacked::Map(self.unpack_map(size)?)
+            PACKED_INT64 => {
+                let a = self.unpack_int64()?;
+                self.check_minimal(!(-0x8000_0000..=0x7fff_ffff).contains(&a))?;
+                UnpackedRef::Int64(a)
+            }
+            PACKED_STR_U16 => {
+                let size = self.unpack_len_u16()?;
+                self.unpack_string(size)?
+            }
+            PACKED_STR_U32 => {
+                let size = self.unpack_len_u32()?;
+                self.unpack_string(size)?
+            }
+            PACKED_RAW_U16 => {
+                let size = self.unpack_len_u16()?;
+                UnpackedRef::Raw(self.unpack_raw(size)?)
             }
+            PACKED_RAW_U32 => {
+                let size = self.unpack_len_u32()?;
+                UnpackedRef::Raw(self.unpack_raw(size)?)
+            }
+            PACKED_ARR_U16 => return Ok(Token::Array(self.unpack_len_u16()?)),
+            PACKED_ARR_U32 => return Ok(Token::Array(self.unpack_len_u32()?)),
+            PACKED_MAP_U16 => return Ok(Token::Map(self.unpack_len_u16()?)),
+            PACKED_MAP_U32 => return Ok(Token::Map(self.unpack_len_u32()?)),
+
+            _ if self.options.strict => return Err(ErrorKind::ReservedType.into()),
+            _ => UnpackedRef::Undefined,
+        }))
+    }
 
-            _ => Unpacked::Undefined,
-        })
+    /// Reads a number as the `Double` a JavaScript peer would see, warning if it is an integer
+    /// outside the safe range
+    fn js_number(&mut self, value: UnpackedRef<'a>) -> UnpackedRef<'a> {
+        let unsafe_integer = match value {
+            UnpackedRef::Uint64(a) if a > MAX_SAFE_INTEGER => Some(a as i128),
+            UnpackedRef::Int64(a) if a.unsigned_abs() > MAX_SAFE_INTEGER => Some(a as i128),
+            _ => None,
+        };
+        if let Some(a) = unsafe_integer {
+            let rounded = (a as f64) as i128 != a;
+            self.warn(WarningKind::UnsafeInteger { rounded });
+        }
+        match value.as_f64() {
+            Some(n) => UnpackedRef::Double(n),
+            None => value,
+        }
+    }
+
+    /// Unpacks a 16 bit length, which in strict mode must not fit in the fix forms
+    fn unpack_len_u16(&mut self) -> Result<usize> {
+        let size = self.unpack_uint16()? as usize;
+        self.check_minimal(size > 0x0f)?;
+        Ok(size)
+    }
+
+    /// Unpacks a 32 bit length, which in strict mode must not fit in 16 bits
+    pub(crate) fn unpack_len_u32(&mut self) -> Result<usize> {
+        let size = self.unpack_uint32()? as usize;
+        self.check_minimal(size > u16::MAX as usize)?;
+        Ok(size)
+    }
+}
+
+impl<'a> Iterator for Unpacker<'a> {
+    type Item = Result<Unpacked>;
+
+    /// Unpacks the next value, returning `None` once the input is exhausted or after the first
+    /// error.
+    fn next(&mut self) -> Option<Result<Unpacked>> {
+        if self.failed || self.data.is_empty() {
+            return None;
+        }
+
+        let result = self.unpack();
+        self.failed = result.is_err();
+        Some(result)
     }
 }
 
@@ -318,159 +1152,437 @@ pub fn unpack(data: &[u8]) -> Result<Unpacked> {
     Unpacker::new(data).unpack()
 }
 
+/// Unpacks data in the js-binarypack format, borrowing strings and raw bytes from `data`
+pub fn unpack_ref(data: &[u8]) -> Result<UnpackedRef<'_>> {
+    Unpacker::new(data).unpack_ref()
+}
+
+/// Unpacks data in the js-binarypack format using `options`
+///
+/// In strict mode this fails if any bytes follow the value.
+pub fn unpack_with_options(data: &[u8], options: &UnpackOptions) -> Result<Unpacked> {
+    let mut unpacker = Unpacker::with_options(data, options.clone());
+    let value = unpacker.unpack()?;
+    unpacker.check_end()?;
+    Ok(value)
+}
+
 impl Unpacked {
-    fn _pack_len(packed: &mut Vec<u8>, size: usize, u16_type: u8, u32_type: u8) {
-        if size <= (u16::max_value() as usize) {
-            packed.push(u16_type);
+    #[allow(clippy::legacy_numeric_constants)]
+    fn _pack_len<W: Write>(
+        packed: &mut W,
+        size: usize,
+        fix_mask: u8,
+        u16_type: u8,
+        u32_type: u8,
+        options: &PackOptions,
+    ) -> Result<()> {
+        if size > u32::MAX as usize {
+            return Err(ErrorKind::TooLarge(size).into());
+        }
+
+        if options.compact && size <= 0x0f {
+            packed.write_all(&[fix_mask | size as u8])?;
+        } else if size <= (u16::max_value() as usize) {
+            packed.write_all(&[u16_type])?;
 
             let mut size_bytes = [0u8; 2];
             BigEndian::write_u16(&mut size_bytes, size as u16);
-            for b in size_bytes.iter() {
-                packed.push(*b);
-            }
+            packed.write_all(&size_bytes)?;
         } else {
-            packed.push(u32_type);
+            packed.write_all(&[u32_type])?;
 
             let mut size_bytes = [0u8; 4];
             BigEndian::write_u32(&mut size_bytes, size as u32);
-            for b in size_bytes.iter() {
-                packed.push(*b);
+            packed.write_all(&size_bytes)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn _pack_raw<W: Write>(
+        packed: &mut W,
+        bytes: &[u8],
+        options: &PackOptions,
+    ) -> Result<()> {
+        let size = bytes.len();
+        Unpacked::_pack_len(
+            packed,
+            size,
+            RAW_MASK,
+            PACKED_RAW_U16,
+            PACKED_RAW_U32,
+            options,
+        )?;
+        packed.write_all(bytes)?;
+        Ok(())
+    }
+
+    pub(crate) fn _pack_str<W: Write>(
+        packed: &mut W,
+        s: &str,
+        options: &PackOptions,
+    ) -> Result<()> {
+        let bytes = s.as_bytes();
+        let size = bytes.len();
+        Unpacked::_pack_len(
+            packed,
+            size,
+            STR_MASK,
+            PACKED_STR_U16,
+            PACKED_STR_U32,
+            options,
+        )?;
+        packed.write_all(bytes)?;
+        Ok(())
+    }
+
+    pub(crate) fn _pack_array_len<W: Write>(
+        packed: &mut W,
+        size: usize,
+        options: &PackOptions,
+    ) -> Result<()> {
+        Unpacked::_pack_len(
+            packed,
+            size,
+            ARR_MASK,
+            PACKED_ARR_U16,
+            PACKED_ARR_U32,
+            options,
+        )
+    }
+
+    pub(crate) fn _pack_map_len<W: Write>(
+        packed: &mut W,
+        size: usize,
+        options: &PackOptions,
+    ) -> Result<()> {
+        Unpacked::_pack_len(
+            packed,
+            size,
+            MAP_MASK,
+            PACKED_MAP_U16,
+            PACKED_MAP_U32,
+            options,
+        )
+    }
+
+    /// Reads any numeric variant as an `f64`, rounding 64 bit integers beyond ±(2^53 - 1) to
+    /// the nearest double. Returns `None` for other variants.
+    pub fn as_f64(&self) -> Option<f64> {
+        Some(match *self {
+            Unpacked::Uint8(a) => a.into(),
+            Unpacked::Uint16(a) => a.into(),
+            Unpacked::Uint32(a) => a.into(),
+            Unpacked::Uint64(a) => a as f64,
+            Unpacked::Int8(a) => a.into(),
+            Unpacked::Int16(a) => a.into(),
+            Unpacked::Int32(a) => a.into(),
+            Unpacked::Int64(a) => a as f64,
+            Unpacked::Float(f) => f.into(),
+            Unpacked::Double(f) => f,
+            _ => return None,
+        })
+    }
+
+    /// The value of an integer variant
+    fn as_integer(&self) -> Option<i128> {
+        Some(match *self {
+            Unpacked::Uint8(a) => a.into(),
+            Unpacked::Uint16(a) => a.into(),
+            Unpacked::Uint32(a) => a.into(),
+            Unpacked::Uint64(a) => a.into(),
+            Unpacked::Int8(a) => a.into(),
+            Unpacked::Int16(a) => a.into(),
+            Unpacked::Int32(a) => a.into(),
+            Unpacked::Int64(a) => a.into(),
+            _ => return None,
+        })
+    }
+
+    /// Packs an integer in the narrowest form that holds it, in the order js-binarypack tries them
+    fn _pack_integer<W: Write>(packed: &mut W, n: i128) -> Result<()> {
+        if (-0x20..=0x7f).contains(&n) {
+            packed.write_all(&[n as u8])?;
+        } else if (0..=0xff).contains(&n) {
+            packed.write_all(&[PACKED_UINT8, n as u8])?;
+        } else if (-0x80..=0x7f).contains(&n) {
+            packed.write_all(&[PACKED_INT8, n as u8])?;
+        } else if (0..=0xffff).contains(&n) {
+            packed.write_all(&[PACKED_UINT16])?;
+            packed.write_all(&(n as u16).to_be_bytes())?;
+        } else if (-0x8000..=0x7fff).contains(&n) {
+            packed.write_all(&[PACKED_INT16])?;
+            packed.write_all(&(n as i16).to_be_bytes())?;
+        } else if (0..=0xffff_ffff).contains(&n) {
+            packed.write_all(&[PACKED_UINT32])?;
+            packed.write_all(&(n as u32).to_be_bytes())?;
+        } else if (-0x8000_0000..=0x7fff_ffff).contains(&n) {
+            packed.write_all(&[PACKED_INT32])?;
+            packed.write_all(&(n as i32).to_be_bytes())?;
+        } else if (i64::MIN.into()..=i64::MAX.into()).contains(&n) {
+            packed.write_all(&[PACKED_INT64])?;
+            packed.write_all(&(n as i64).to_be_bytes())?;
+        } else {
+            packed.write_all(&[PACKED_UINT64])?;
+            packed.write_all(&(n as u64).to_be_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Packs a single token, writing only the header of an array or map
+    pub(crate) fn _pack_token<W: Write>(
+        packed: &mut W,
+        token: &Token,
+        options: &PackOptions,
+    ) -> Result<()> {
+        match token {
+            Token::Array(size) => Unpacked::_pack_array_len(packed, *size, options),
+            Token::Map(size) => Unpacked::_pack_map_len(packed, *size, options),
+            Token::Value(UnpackedRef::Raw(bytes)) => Unpacked::_pack_raw(packed, bytes, options),
+            Token::Value(UnpackedRef::String(s)) => Unpacked::_pack_str(packed, s, options),
+            Token::Value(value) => value.clone().into_owned()._pack(packed, options),
+            Token::Extension(type_) => Err(ErrorKind::Extension(*type_).into()),
+        }
+    }
+
+    /// Passes the tokens of the value to `visit` in the order they are packed, without copying
+    /// its strings or raw bytes
+    pub(crate) fn visit_tokens<F>(&self, visit: &mut F) -> Result<()>
+    where
+        F: FnMut(&Token) -> Result<()>,
+    {
+        let value = match self {
+            Unpacked::Array(v) => {
+                visit(&Token::Array(v.len()))?;
+                for element in v {
+                    element.visit_tokens(visit)?;
+                }
+                return Ok(());
             }
+            Unpacked::Map(m) => {
+                visit(&Token::Map(m.len()))?;
+                for (key, value) in m {
+                    key.visit_tokens(visit)?;
+                    value.visit_tokens(visit)?;
+                }
+                return Ok(());
+            }
+            Unpacked::Uint8(a) => UnpackedRef::Uint8(*a),
+            Unpacked::Uint16(a) => UnpackedRef::Uint16(*a),
+            Unpacked::Uint32(a) => UnpackedRef::Uint32(*a),
+            Unpacked::Uint64(a) => UnpackedRef::Uint64(*a),
+            Unpacked::Int8(a) => UnpackedRef::Int8(*a),
+            Unpacked::Int16(a) => UnpackedRef::Int16(*a),
+            Unpacked::Int32(a) => UnpackedRef::Int32(*a),
+            Unpacked::Int64(a) => UnpackedRef::Int64(*a),
+            Unpacked::Float(f) => UnpackedRef::Float(*f),
+            Unpacked::Double(f) => UnpackedRef::Double(*f),
+            Unpacked::Bool(b) => UnpackedRef::Bool(*b),
+            Unpacked::Raw(bytes) => UnpackedRef::Raw(bytes),
+            Unpacked::String(s) => UnpackedRef::String(Cow::Borrowed(s)),
+            Unpacked::Null => UnpackedRef::Null,
+            Unpacked::Undefined => UnpackedRef::Undefined,
+        };
+        visit(&Token::Value(value))
+    }
+
+    /// Packs a number as a JavaScript peer reads it, for `PackOptions::js_numbers`
+    fn _pack_js_number<W: Write>(packed: &mut W, integer: Option<i128>, f: f64) -> Result<()> {
+        if let Some(n) = integer {
+            if n.unsigned_abs() > MAX_SAFE_INTEGER.into() {
+                return Err(ErrorKind::UnsafeInteger(n).into());
+            }
+            Unpacked::_pack_integer(packed, n)
+        } else if f.fract() == 0.0 && f >= i64::MIN as f64 && f < u64::MAX as f64 {
+            Unpacked::_pack_integer(packed, f as i128)
+        } else {
+            packed.write_all(&[PACKED_DOUBLE])?;
+            packed.write_all(&f.to_be_bytes())?;
+            Ok(())
         }
     }
 
-    fn _pack(&self, packed: &mut Vec<u8>) {
+    pub(crate) fn _pack<W: Write>(&self, packed: &mut W, options: &PackOptions) -> Result<()> {
+        if options.js_numbers {
+            if let Some(f) = self.as_f64() {
+                return Unpacked::_pack_js_number(packed, self.as_integer(), f);
+            }
+        }
+        if options.narrow_integers {
+            if let Some(n) = self.as_integer() {
+                return Unpacked::_pack_integer(packed, n);
+            }
+        }
+
         match self {
             Unpacked::Uint8(a) => {
                 if *a < MAP_MASK {
-                    packed.push(*a);
+                    packed.write_all(&[*a])?;
                 } else {
-                    packed.push(PACKED_UINT8);
-                    packed.push(*a);
+                    packed.write_all(&[PACKED_UINT8])?;
+                    packed.write_all(&[*a])?;
                 }
             }
             Unpacked::Uint16(a) => {
-                packed.push(PACKED_UINT16);
+                packed.write_all(&[PACKED_UINT16])?;
                 let mut bytes = [0u8; 2];
                 BigEndian::write_u16(&mut bytes, *a);
-                for b in bytes.iter() {
-                    packed.push(*b);
-                }
+                packed.write_all(&bytes)?;
             }
             Unpacked::Uint32(a) => {
-                packed.push(PACKED_UINT32);
+                packed.write_all(&[PACKED_UINT32])?;
                 let mut bytes = [0u8; 4];
                 BigEndian::write_u32(&mut bytes, *a);
-                for b in bytes.iter() {
-                    packed.push(*b);
-                }
+                packed.write_all(&bytes)?;
             }
             Unpacked::Uint64(a) => {
-                packed.push(PACKED_UINT64);
+                packed.write_all(&[PACKED_UINT64])?;
                 let mut bytes = [0u8; 8];
                 BigEndian::write_u64(&mut bytes, *a);
-                for b in bytes.iter() {
-                    packed.push(*b);
-                }
+                packed.write_all(&bytes)?;
             }
             Unpacked::Int8(a) => {
                 if *a < 0 && *a > -0x20 {
-                    packed.push(((a + 0x20) as u8) ^ INT_MASK)
+                    packed.write_all(&[((a + 0x20) as u8) ^ INT_MASK])?;
                 } else {
-                    packed.push(PACKED_INT8);
-                    packed.push(*a as u8);
+                    packed.write_all(&[PACKED_INT8])?;
+                    packed.write_all(&[*a as u8])?;
                 }
             }
             Unpacked::Int16(a) => {
-                packed.push(PACKED_INT16);
+                packed.write_all(&[PACKED_INT16])?;
                 let mut bytes = [0u8; 2];
                 BigEndian::write_u16(&mut bytes, *a as u16);
-                for b in bytes.iter() {
-                    packed.push(*b);
-                }
+                packed.write_all(&bytes)?;
             }
             Unpacked::Int32(a) => {
-                packed.push(PACKED_INT32);
+                packed.write_all(&[PACKED_INT32])?;
                 let mut bytes = [0u8; 4];
                 BigEndian::write_u32(&mut bytes, *a as u32);
-                for b in bytes.iter() {
-                    packed.push(*b);
-                }
+                packed.write_all(&bytes)?;
             }
             Unpacked::Int64(a) => {
-                packed.push(PACKED_INT64);
+                packed.write_all(&[PACKED_INT64])?;
                 let mut bytes = [0u8; 8];
                 BigEndian::write_u64(&mut bytes, *a as u64);
-                for b in bytes.iter() {
-                    packed.push(*b);
-                }
+                packed.write_all(&bytes)?;
             }
             Unpacked::Float(f) => {
                 let mut bytes = [0u8; 4];
                 BigEndian::write_f32(&mut bytes, *f);
 
-                packed.push(PACKED_FLOAT);
-                for b in bytes.iter() {
-                    packed.push(*b);
-                }
+                packed.write_all(&[PACKED_FLOAT])?;
+                packed.write_all(&bytes)?;
             }
             Unpacked::Double(f) => {
                 let mut bytes = [0u8; 8];
                 BigEndian::write_f64(&mut bytes, *f);
 
-                packed.push(PACKED_DOUBLE);
-                for b in bytes.iter() {
-                    packed.push(*b);
-                }
+                packed.write_all(&[PACKED_DOUBLE])?;
+                packed.write_all(&bytes)?;
             }
             Unpacked::Bool(b) => {
                 match b {
-                    true => packed.push(PACKED_TRUE),
-                    false => packed.push(PACKED_FALSE),
+                    true => packed.write_all(&[PACKED_TRUE])?,
+                    false => packed.write_all(&[PACKED_FALSE])?,
                 };
             }
-            Unpacked::Raw(bytes) => {
-                Unpacked::_pack_len(packed, bytes.len(), PACKED_RAW_U16, PACKED_RAW_U32);
-                for b in bytes.iter() {
-                    packed.push(*b);
-                }
-            }
-            Unpacked::String(s) => {
-                let bytes = s.bytes();
-                Unpacked::_pack_len(packed, bytes.len(), PACKED_STR_U16, PACKED_STR_U32);
-                for b in bytes {
-                    packed.push(b);
-                }
-            }
+            Unpacked::Raw(bytes) => Unpacked::_pack_raw(packed, bytes, options)?,
+            Unpacked::String(s) => Unpacked::_pack_str(packed, s, options)?,
             Unpacked::Null => {
-                packed.push(PACKED_NULL);
+                packed.write_all(&[PACKED_NULL])?;
             }
-            Unpacked::Undefined => packed.push(PACKED_UNDEFINED),
+            Unpacked::Undefined => packed.write_all(&[PACKED_UNDEFINED])?,
             Unpacked::Array(v) => {
-                Unpacked::_pack_len(packed, v.len(), PACKED_ARR_U16, PACKED_ARR_U32);
+                Unpacked::_pack_array_len(packed, v.len(), options)?;
                 for element in v {
-                    element._pack(packed);
+                    element._pack(packed, options)?;
+                }
+            }
+            Unpacked::Map(m) if options.canonical => {
+                let mut entries = m
+                    .iter()
+                    .map(|(key, value)| {
+                        Ok((key.try_pack_with(options)?, value.try_pack_with(options)?))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                entries.sort();
+
+                Unpacked::_pack_map_len(packed, m.len(), options)?;
+                for (key, value) in entries {
+                    packed.write_all(&key)?;
+                    packed.write_all(&value)?;
                 }
             }
             Unpacked::Map(m) => {
-                Unpacked::_pack_len(packed, m.len(), PACKED_MAP_U16, PACKED_MAP_U32);
+                Unpacked::_pack_map_len(packed, m.len(), options)?;
                 for (key, value) in m {
-                    key._pack(packed);
-                    value._pack(packed);
+                    key._pack(packed, options)?;
+                    value._pack(packed, options)?;
                 }
             }
         }
+
+        Ok(())
     }
 
     /// Pack a value into the js-binarypack format
+    ///
+    /// # Panics
+    ///
+    /// Panics if a string, raw, array or map in the value is too large for the format, see
+    /// `try_pack`.
     pub fn pack(&self) -> Vec<u8> {
+        match self.try_pack() {
+            Ok(packed) => packed,
+            Err(e) => panic!("cannot pack value: {}", e),
+        }
+    }
+
+    /// Pack a value into the js-binarypack format, failing with `ErrorKind::TooLarge` if a
+    /// string, raw, array or map in it is longer than the format's limit of `u32::MAX`
+    pub fn try_pack(&self) -> Result<Vec<u8>> {
+        self.try_pack_with(&PackOptions::default())
+    }
+
+    /// Pack a value into the js-binarypack format using `options`
+    ///
+    /// Like `try_pack`, this fails if a string, raw, array or map is too large for the format.
+    pub fn try_pack_with(&self, options: &PackOptions) -> Result<Vec<u8>> {
         let mut packed = vec![];
-        self._pack(&mut packed);
-        packed
+        self._pack(&mut packed, options)?;
+        Ok(packed)
+    }
+
+    /// Pack a value with its map entries sorted, so that equal values always produce the same
+    /// bytes
+    ///
+    /// # Panics
+    ///
+    /// Panics if a string, raw, array or map in the value is too large for the format.
+    pub fn pack_canonical(&self) -> Vec<u8> {
+        let options = PackOptions {
+            canonical: true,
+            ..Default::default()
+        };
+        match self.try_pack_with(&options) {
+            Ok(packed) => packed,
+            Err(e) => panic!("cannot pack value: {}", e),
+        }
     }
 }
 
 #[cfg(test)]
+#[allow(
+    clippy::legacy_numeric_constants,
+    clippy::match_like_matches_macro,
+    clippy::same_item_push,
+    clippy::fn_to_numeric_cast_with_truncation,
+    clippy::confusing_method_to_numeric_cast,
+    function_casts_as_integer
+)]
 mod test {
     use super::*;
 
@@ -532,32 +1644,32 @@ mod test {
         ];
         assert_eq!(
             Unpacker::new(&a).unpack_string(a.len()).unwrap(),
-            "hello world!"
+            UnpackedRef::String("hello world!".into())
         );
     }
 
     #[test]
     fn test_unpack_array() {
-        let a = [1, 2, 3, 4, 5];
+        let a = [0x95, 1, 2, 3, 4, 5];
         assert_eq!(
-            Unpacker::new(&a).unpack_array(a.len()).unwrap(),
-            vec!(
+            Unpacker::new(&a).unpack().unwrap(),
+            Unpacked::Array(vec!(
                 Unpacked::Uint8(1),
                 Unpacked::Uint8(2),
                 Unpacked::Uint8(3),
                 Unpacked::Uint8(4),
                 Unpacked::Uint8(5)
-            )
+            ))
         );
     }
 
     #[test]
     fn test_unpack_map() {
-        let a = [1, 2, 3, 4];
+        let a = [0x82, 1, 2, 3, 4];
         let mut expected = HashMap::new();
         expected.insert(Unpacked::Uint8(1), Unpacked::Uint8(2));
         expected.insert(Unpacked::Uint8(3), Unpacked::Uint8(4));
-        assert_eq!(Unpacker::new(&a).unpack_map(a.len() / 2).unwrap(), expected);
+        assert_eq!(Unpacker::new(&a).unpack().unwrap(), Unpacked::Map(expected));
     }
 
     #[test]
@@ -699,6 +1811,308 @@ mod test {
         assert!(Unpacker::new(&packed).unpack().unwrap().is_undefined());
     }
 
+    #[test]
+    fn test_unpacker_iter() {
+        let packed = [1, 2 ^ 0xb0, 65, 66, 0xc0];
+        let mut unpacker = Unpacker::new(&packed);
+        assert_eq!(unpacker.next().unwrap().unwrap(), Unpacked::Uint8(1));
+        assert_eq!(unpacker.offset(), 1);
+        assert_eq!(
+            unpacker.next().unwrap().unwrap(),
+            Unpacked::String("AB".to_string())
+        );
+        assert_eq!(unpacker.offset(), 4);
+        assert_eq!(unpacker.remaining(), &[0xc0]);
+        assert_eq!(unpacker.next().unwrap().unwrap(), Unpacked::Null);
+        assert!(unpacker.is_empty());
+        assert!(unpacker.next().is_none());
+
+        let packed = [1, 0xcd, 1];
+        let values: Vec<_> = Unpacker::new(&packed).collect();
+        assert_eq!(values.len(), 2);
+        assert_eq!(*values[0].as_ref().unwrap(), Unpacked::Uint8(1));
+        assert!(values[1].is_err());
+
+        let mut unpacker = Unpacker::new(&packed);
+        unpacker.unpack().unwrap();
+        assert!(unpacker.unpack().is_err());
+        assert_eq!(unpacker.offset(), 1);
+        assert_eq!(unpacker.remaining(), &[0xcd, 1]);
+    }
+
+    #[test]
+    fn test_unpack_ref() {
+        let packed = [
+            3 ^ 0x90,
+            2 ^ 0xa0,
+            1,
+            2,
+            2 ^ 0xb0,
+            65,
+            66,
+            1 ^ 0x80,
+            1 ^ 0xb0,
+            67,
+            0xc0,
+        ];
+        let unpacked = unpack_ref(&packed).unwrap();
+        assert_eq!(
+            unpacked,
+            UnpackedRef::Array(vec!(
+                UnpackedRef::Raw(&packed[2..4]),
+                UnpackedRef::String("AB".into()),
+                UnpackedRef::Map(vec!((UnpackedRef::String("C".into()), UnpackedRef::Null)))
+            ))
+        );
+        if let UnpackedRef::Array(v) = &unpacked {
+            if let UnpackedRef::Raw(raw) = v[0] {
+                assert_eq!(raw.as_ptr(), packed[2..].as_ptr());
+            }
+        }
+        assert_eq!(unpacked.into_owned(), unpack(&packed).unwrap());
+
+        let packed = [2 ^ 0xb0, 0xff, 0xfe];
+        let mut unpacker = Unpacker::new(&packed);
+        assert!(unpacker.unpack_ref().is_err());
+        assert_eq!(unpacker.offset(), 0);
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let mut packed = vec![0x91; 10];
+        packed.push(0xc0);
+        let options = UnpackOptions {
+            max_depth: 10,
+            ..Default::default()
+        };
+        assert!(unpack_with_options(&packed, &options).is_ok());
+        assert!(Unpacker::with_options(&packed, options.clone())
+            .unpack_ref()
+            .is_ok());
+
+        let options = UnpackOptions {
+            max_depth: 9,
+            ..Default::default()
+        };
+        let e = unpack_with_options(&packed, &options).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::DepthLimitExceeded(9)));
+        assert_eq!(e.offset(), Some(9));
+        assert_eq!(e.path().len(), 9);
+
+        let mut unpacker = Unpacker::with_options(&packed, options);
+        assert!(unpacker.unpack_ref().is_err());
+        assert_eq!(unpacker.offset(), 0);
+
+        let packed = vec![0x91; 1_000_000];
+        let e = unpack(&packed).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::DepthLimitExceeded(128)));
+
+        // Without a limit, nesting is only bounded by the input
+        let options = UnpackOptions {
+            max_depth: usize::MAX,
+            ..Default::default()
+        };
+        let e = unpack_with_options(&packed, &options).unwrap_err();
+        assert!(e.is_end_of_data());
+        assert_eq!(e.path().len(), packed.len());
+    }
+
+    #[test]
+    fn test_strict() {
+        let strict = UnpackOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let value = binarypack!({ "a": [1, -1i8, 200u8, "text", b"raw", null] });
+        let js = PackOptions {
+            compact: true,
+            narrow_integers: true,
+            ..Default::default()
+        };
+        let packed = value.try_pack_with(&js).unwrap();
+        assert_eq!(
+            unpack_with_options(&packed, &strict).unwrap(),
+            binarypack!({ "a": [1u8, -1i8, 200u8, "text", b"raw", null] })
+        );
+        let e = unpack_with_options(&value.pack(), &strict).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::NonMinimalEncoding));
+
+        for type_ in (0xc4..=0xc9).chain(0xd4..=0xd7) {
+            assert!(unpack(&[type_]).unwrap().is_undefined());
+            let e = unpack_with_options(&[type_], &strict).unwrap_err();
+            assert!(matches!(e.kind(), ErrorKind::ReservedType));
+            assert_eq!(e.type_byte(), Some(type_));
+        }
+
+        let mut trailing = packed.clone();
+        trailing.push(0xc0);
+        assert!(unpack_with_options(&trailing, &Default::default()).is_ok());
+        let e = unpack_with_options(&trailing, &strict).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::TrailingData));
+        assert_eq!(e.offset(), Some(packed.len()));
+
+        for packed in [
+            &[0xcc, 0x7f][..],
+            &[0xd0, 0xff],
+            &[0xd9, 0, 0, 0, 1, 0x61],
+            &[0xdb, 0, 0, 0, 0],
+            &[0xdd, 0, 0, 0, 0],
+            &[0xdf, 0, 0, 0, 0],
+            &[0xcd, 0, 1],
+            &[0xce, 0, 0, 0xff, 0xff],
+            &[0xcf, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff],
+            &[0xd1, 0xff, 0x80],
+            &[0xd1, 0, 0x7f],
+            &[0xd2, 0xff, 0xff, 0x80, 0],
+            &[0xd3, 0, 0, 0, 0, 0x7f, 0xff, 0xff, 0xff],
+            &[0xd3, 0xff, 0xff, 0xff, 0xff, 0x80, 0, 0, 0],
+            &[0xd8, 0, 1, 0x61],
+            &[0xda, 0, 1, 0x61],
+            &[0xdc, 0, 0],
+            &[0xde, 0, 0],
+        ] {
+            assert!(unpack(packed).is_ok());
+            let e = unpack_with_options(packed, &strict).unwrap_err();
+            assert!(matches!(e.kind(), ErrorKind::NonMinimalEncoding));
+        }
+        assert!(unpack_with_options(&[0xd0, 0x05], &strict).is_ok());
+        assert!(unpack_with_options(&[0xd0, 0xe0], &strict).is_ok());
+        for packed in [
+            &[0xcd, 1, 0][..],
+            &[0xce, 0, 1, 0, 0],
+            &[0xcf, 0, 0, 0, 1, 0, 0, 0, 0],
+            &[0xd1, 0xff, 0x7f],
+            &[0xd2, 0xff, 0xff, 0x7f, 0xff],
+            &[0xd3, 0xff, 0xff, 0xff, 0xff, 0x7f, 0xff, 0xff, 0xff],
+        ] {
+            assert!(unpack_with_options(packed, &strict).is_ok());
+        }
+        let mut long = vec![0xd8, 0, 0x10];
+        long.extend_from_slice(&[0x61; 16]);
+        assert!(unpack_with_options(&long, &strict).is_ok());
+
+        let packed = binarypack!({ "ok": { 1: 2 } }).try_pack_with(&js).unwrap();
+        assert!(unpack(&packed).is_ok());
+        let e = unpack_with_options(&packed, &strict).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::NonStringKey));
+        assert_eq!(
+            e.path(),
+            &[
+                PathSegment::Key(Unpacked::String("ok".to_string())),
+                PathSegment::MapKey(0)
+            ]
+        );
+        let e = Unpacker::with_options(&packed, strict)
+            .unpack_ref()
+            .unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::NonStringKey));
+    }
+
+    #[test]
+    fn test_invalid_utf8() {
+        let packed = [0x92, 0xd8, 0, 3, 0x61, 0xff, 0x62, 0xd8, 0, 1, 0x63];
+        let e = unpack(&packed).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::StringParseError));
+
+        let options = UnpackOptions {
+            invalid_utf8: InvalidUtf8::Replace,
+            ..Default::default()
+        };
+        let mut unpacker = Unpacker::with_options(&packed, options);
+        assert_eq!(unpacker.unpack().unwrap(), binarypack!(["a\u{fffd}b", "c"]));
+        assert_eq!(
+            unpacker.take_warnings(),
+            vec![Warning::new(WarningKind::ReplacedInvalidUtf8, 1)]
+        );
+        assert!(unpacker.warnings().is_empty());
+
+        let options = UnpackOptions {
+            invalid_utf8: InvalidUtf8::Raw,
+            ..Default::default()
+        };
+        let mut unpacker = Unpacker::with_options(&packed, options.clone());
+        assert_eq!(
+            unpacker.unpack_ref().unwrap(),
+            UnpackedRef::Array(vec![
+                UnpackedRef::Raw(&packed[4..7]),
+                UnpackedRef::String("c".into())
+            ])
+        );
+        assert_eq!(
+            unpacker.warnings(),
+            &[Warning::new(WarningKind::InvalidUtf8AsRaw, 1)]
+        );
+
+        // Warnings from a value that fails are discarded along with it
+        let mut unpacker = Unpacker::with_options(&packed[..packed.len() - 1], options);
+        assert!(unpacker.unpack().is_err());
+        assert!(unpacker.warnings().is_empty());
+    }
+
+    #[test]
+    fn test_budgets() {
+        let value = binarypack!({ "list": [1, 2, 3], "text": "hello" });
+        let packed = value.pack();
+
+        let options = UnpackOptions {
+            max_elements: Some(8),
+            max_string_len: Some(5),
+            max_total_bytes: Some(packed.len()),
+            max_map_len: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(unpack_with_options(&packed, &options).unwrap(), value);
+
+        let options = UnpackOptions {
+            max_elements: Some(7),
+            ..Default::default()
+        };
+        let e = unpack_with_options(&packed, &options).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::ElementLimitExceeded(7)));
+
+        let options = UnpackOptions {
+            max_string_len: Some(4),
+            ..Default::default()
+        };
+        let e = unpack_with_options(&packed, &options).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::StringLengthLimitExceeded(4)));
+
+        let options = UnpackOptions {
+            max_total_bytes: Some(packed.len() - 1),
+            ..Default::default()
+        };
+        let e = unpack_with_options(&packed, &options).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::ByteLimitExceeded(_)));
+
+        let options = UnpackOptions {
+            max_map_len: Some(1),
+            ..Default::default()
+        };
+        let e = unpack_with_options(&packed, &options).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::MapLengthLimitExceeded(1)));
+        assert_eq!(e.offset(), Some(0));
+
+        // The budget applies to each value separately
+        let options = UnpackOptions {
+            max_elements: Some(2),
+            ..Default::default()
+        };
+        let packed = [binarypack!([1]).pack(), binarypack!([2]).pack()].concat();
+        let mut unpacker = Unpacker::with_options(&packed, options.clone());
+        assert!(unpacker.unpack().is_ok());
+        assert!(unpacker.unpack_ref().is_ok());
+
+        // Sizes are checked against the header, before the contents are read
+        let e = unpack_with_options(&[0xdd, 0xff, 0xff, 0xff, 0xff], &options).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::ElementLimitExceeded(2)));
+        let options = UnpackOptions {
+            max_string_len: Some(16),
+            ..Default::default()
+        };
+        let e = unpack_with_options(&[0xdb, 0xff, 0xff, 0xff, 0xff], &options).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::StringLengthLimitExceeded(16)));
+    }
+
     #[test]
     fn pack_uint8() {
         assert_eq!(Unpacked::Uint8(0x79).pack(), vec!(0x79));
@@ -897,4 +2311,240 @@ mod test {
             .unwrap()
             .is_undefined());
     }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn pack_too_large() {
+        let mut packed = vec![];
+        let options = PackOptions::default();
+        let e = Unpacked::_pack_array_len(&mut packed, 1 << 32, &options).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::TooLarge(0x1_0000_0000)));
+        assert!(packed.is_empty());
+        Unpacked::_pack_map_len(&mut packed, u32::MAX as usize, &options).unwrap();
+        assert_eq!(packed, vec![0xdf, 0xff, 0xff, 0xff, 0xff]);
+
+        let value = binarypack!([1, "two", { "three": null }]);
+        assert_eq!(value.try_pack().unwrap(), value.pack());
+    }
+
+    #[test]
+    fn pack_canonical() {
+        let mut m = HashMap::new();
+        for i in 0..100u8 {
+            m.insert(Unpacked::Uint8(i), binarypack!({ i: i, "x": [i] }));
+        }
+        m.insert(Unpacked::Undefined, Unpacked::Uint8(2));
+        m.insert(Unpacked::Undefined, Unpacked::Uint8(1));
+        let value = Unpacked::Map(m);
+
+        let packed = value.pack_canonical();
+        let copy = unpack(&packed).unwrap();
+        assert_eq!(copy.pack_canonical(), packed);
+
+        let mut unpacker = Unpacker::new(&packed);
+        let entries = match unpacker.unpack_ref().unwrap() {
+            UnpackedRef::Map(entries) => entries,
+            _ => unreachable!(),
+        };
+        assert_eq!(entries.len(), 102);
+        assert_eq!(entries[0], (UnpackedRef::Uint8(0), entries[0].1.clone()));
+        assert_eq!(entries[99].0, UnpackedRef::Uint8(99));
+        assert_eq!(
+            entries[100],
+            (UnpackedRef::Undefined, UnpackedRef::Uint8(1))
+        );
+        assert_eq!(
+            entries[101],
+            (UnpackedRef::Undefined, UnpackedRef::Uint8(2))
+        );
+    }
+
+    #[test]
+    fn pack_compact() {
+        let options = PackOptions {
+            compact: true,
+            ..Default::default()
+        };
+        let value = binarypack!([{ "key": b"raw" }]);
+        let packed = value.try_pack_with(&options).unwrap();
+        assert_eq!(
+            packed,
+            vec![0x91, 0x81, 0xb3, b'k', b'e', b'y', 0xa3, b'r', b'a', b'w']
+        );
+        assert_eq!(unpack(&packed).unwrap(), value);
+        assert_eq!(value.pack().len(), packed.len() + 8);
+
+        let long = Unpacked::String("x".repeat(16));
+        let packed = long.try_pack_with(&options).unwrap();
+        assert_eq!(packed[..3], [0xd8, 0, 16]);
+        assert_eq!(packed, long.pack());
+        let empty = Unpacked::Array(vec![]);
+        assert_eq!(empty.try_pack_with(&options).unwrap(), vec![0x90]);
+    }
+
+    #[test]
+    fn pack_narrow_integers() {
+        let options = PackOptions {
+            narrow_integers: true,
+            ..Default::default()
+        };
+        let cases: Vec<(Unpacked, Vec<u8>)> = vec![
+            (Unpacked::Uint32(3), vec![0x03]),
+            (Unpacked::Int64(-32), vec![0xe0]),
+            (Unpacked::Int64(-1), vec![0xff]),
+            (Unpacked::Uint16(200), vec![0xcc, 200]),
+            (Unpacked::Int32(-33), vec![0xd0, 0xdf]),
+            (Unpacked::Int64(300), vec![0xcd, 0x01, 0x2c]),
+            (Unpacked::Int64(-300), vec![0xd1, 0xfe, 0xd4]),
+            (Unpacked::Uint64(0x1_0000), vec![0xce, 0, 1, 0, 0]),
+            (Unpacked::Int64(-0x1_0000), vec![0xd2, 0xff, 0xff, 0, 0]),
+            (
+                Unpacked::Uint64(0x1_0000_0000),
+                vec![0xd3, 0, 0, 0, 1, 0, 0, 0, 0],
+            ),
+            (
+                Unpacked::Uint64(u64::MAX),
+                vec![0xcf, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            ),
+            (
+                Unpacked::Int64(i64::MIN),
+                vec![0xd3, 0x80, 0, 0, 0, 0, 0, 0, 0],
+            ),
+        ];
+        for (value, expected) in cases {
+            let packed = value.try_pack_with(&options).unwrap();
+            assert_eq!(packed, expected, "{:?}", value);
+            assert_eq!(
+                unpack(&packed).unwrap().as_integer(),
+                value.as_integer(),
+                "{:?}",
+                value
+            );
+        }
+
+        let value = binarypack!([1.5, "x"]);
+        assert_eq!(value.try_pack_with(&options).unwrap(), value.pack());
+    }
+
+    #[test]
+    fn pack_js_numbers() {
+        let options = PackOptions {
+            js_numbers: true,
+            ..Default::default()
+        };
+        let cases: Vec<(Unpacked, Vec<u8>)> = vec![
+            (Unpacked::Uint32(3), vec![0x03]),
+            (Unpacked::Double(3.0), vec![0x03]),
+            (Unpacked::Double(-0.0), vec![0x00]),
+            (Unpacked::Float(-300.0), vec![0xd1, 0xfe, 0xd4]),
+            (
+                Unpacked::Double(9007199254740991.0),
+                vec![0xd3, 0, 0x1f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            ),
+            (
+                Unpacked::Float(1.5),
+                vec![0xcb, 0x3f, 0xf8, 0, 0, 0, 0, 0, 0],
+            ),
+            (
+                Unpacked::Double(2f64.powi(60)),
+                vec![0xd3, 0x10, 0, 0, 0, 0, 0, 0, 0],
+            ),
+            (
+                Unpacked::Double(-(2f64.powi(63))),
+                vec![0xd3, 0x80, 0, 0, 0, 0, 0, 0, 0],
+            ),
+            (
+                Unpacked::Double(2f64.powi(63)),
+                vec![0xcf, 0x80, 0, 0, 0, 0, 0, 0, 0],
+            ),
+            (
+                Unpacked::Double(2f64.powi(64)),
+                vec![0xcb, 0x43, 0xf0, 0, 0, 0, 0, 0, 0],
+            ),
+            (
+                Unpacked::Double(f64::INFINITY),
+                vec![0xcb, 0x7f, 0xf0, 0, 0, 0, 0, 0, 0],
+            ),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(
+                value.try_pack_with(&options).unwrap(),
+                expected,
+                "{:?}",
+                value
+            );
+        }
+
+        let nan = Unpacked::Double(f64::NAN).try_pack_with(&options).unwrap();
+        assert_eq!(nan[0], PACKED_DOUBLE);
+        assert!(unpack(&nan).unwrap().as_f64().unwrap().is_nan());
+
+        for value in [Unpacked::Uint64(1 << 60), Unpacked::Int64(-(1 << 53))] {
+            let e = binarypack!({ "n": [value.clone()] })
+                .try_pack_with(&options)
+                .unwrap_err();
+            assert!(
+                matches!(e.kind(), ErrorKind::UnsafeInteger(n) if Some(*n) == value.as_integer())
+            );
+        }
+        Unpacked::Int64(-(1 << 53) + 1)
+            .try_pack_with(&options)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_js_numbers() {
+        assert_eq!(Unpacked::Uint16(258).as_f64(), Some(258.0));
+        assert_eq!(Unpacked::Float(0.5).as_f64(), Some(0.5));
+        assert_eq!(UnpackedRef::Int8(-1).as_f64(), Some(-1.0));
+        assert_eq!(Unpacked::Uint64(u64::MAX).as_f64(), Some(2f64.powi(64)));
+        assert_eq!(Unpacked::String("1".to_string()).as_f64(), None);
+
+        let options = UnpackOptions {
+            js_numbers: true,
+            ..Default::default()
+        };
+        let packed = binarypack!([
+            3u8,
+            Unpacked::Uint32(3),
+            3.0,
+            Unpacked::Float(0.5),
+            Unpacked::Uint64(1 << 60),
+            Unpacked::Int64(-(1 << 53) + 1),
+            Unpacked::Int64(-(1 << 53) - 1),
+            "3"
+        ])
+        .pack();
+        let mut unpacker = Unpacker::with_options(&packed, options);
+        assert_eq!(
+            unpacker.unpack().unwrap(),
+            binarypack!([
+                3.0,
+                3.0,
+                3.0,
+                0.5,
+                2f64.powi(60),
+                -9007199254740991.0,
+                -9007199254740992.0,
+                "3"
+            ])
+        );
+        let warnings = unpacker.take_warnings();
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(
+            warnings[0].kind(),
+            &WarningKind::UnsafeInteger { rounded: false }
+        );
+        assert_eq!(warnings[0].offset(), 23);
+        assert_eq!(
+            warnings[1].kind(),
+            &WarningKind::UnsafeInteger { rounded: true }
+        );
+        assert_eq!(
+            warnings[1].to_string(),
+            "integer too large for a JavaScript number was rounded at byte 41"
+        );
+
+        assert_eq!(unpack(&packed).unwrap().as_f64(), None);
+    }
 }
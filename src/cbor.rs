@@ -0,0 +1,434 @@
+//! Write `Unpacked` values as CBOR, and read CBOR into them
+//!
+//! Values map onto CBOR (RFC 8949) as follows:
+//!
+//! * `Raw` is a byte string and `String` a text string
+//! * `Null` and `Undefined` are the simple values null and undefined
+//! * `Float` and `Double` are single and double precision floats, and half precision floats
+//!   read as `Float`
+//! * integers keep the width of their variant as the width of their argument, so that
+//!   `Uint16(5)` is written as `0x19 0x00 0x05` and reads back as a `Uint16`. A negative
+//!   integer reads back as the narrowest signed variant at least as wide that holds it, and a
+//!   non-negative one as the unsigned variant, so `Int16(5)` reads back as `Uint16(5)`. An
+//!   integer that fits in the initial byte counts as 8 bits wide.
+//!
+//! Reading, tags are ignored and the value they tag read on its own, recording a `Warning`.
+//! Simple values other than false, true, null and undefined read as `Undefined`. Items of
+//! indefinite length fail with `ErrorKind::IndefiniteLength`, and negative integers below
+//! `i64::MIN` with `ErrorKind::NoEquivalent`.
+//!
+//! `UnpackOptions` apply as they do to js-binarypack. In strict mode tags fail with
+//! `ErrorKind::Tag` and unassigned simple values with `ErrorKind::ReservedType`, and the length
+//! of every string, array and map must be written in the shortest form that holds it.
+
+use std::convert::TryFrom;
+use std::io::Write;
+
+use crate::binarypack::{Format, Token, UnpackOptions, Unpacked, UnpackedRef, Unpacker};
+use crate::error::{ErrorKind, Result, WarningKind};
+
+const UNSIGNED: u8 = 0;
+const NEGATIVE: u8 = 1;
+const BYTES: u8 = 2;
+const TEXT: u8 = 3;
+const ARRAY: u8 = 4;
+const MAP: u8 = 5;
+const TAG: u8 = 6;
+const SIMPLE: u8 = 7;
+
+const FALSE: u8 = 20;
+const TRUE: u8 = 21;
+const NULL: u8 = 22;
+const UNDEFINED: u8 = 23;
+const SIMPLE8: u8 = 24;
+const FLOAT16: u8 = 25;
+const FLOAT32: u8 = 26;
+const FLOAT64: u8 = 27;
+const INDEFINITE: u8 = 31;
+
+/// The number of bytes after the initial byte that the shortest form of an argument takes up
+fn argument_width(n: u64) -> usize {
+    if n < 24 {
+        0
+    } else if n <= u8::MAX.into() {
+        1
+    } else if n <= u16::MAX.into() {
+        2
+    } else if n <= u32::MAX.into() {
+        4
+    } else {
+        8
+    }
+}
+
+/// Converts a half precision float to single precision, which holds every value exactly
+fn f16_to_f32(h: u16) -> f32 {
+    let sign = if h & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = i32::from((h >> 10) & 0x1f);
+    let mantissa = f32::from(h & 0x3ff);
+    sign * match exponent {
+        0 => mantissa * 2f32.powi(-24),
+        0x1f if mantissa == 0.0 => f32::INFINITY,
+        0x1f => f32::NAN,
+        _ => (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+/// The narrowest signed variant at least `width` bytes wide that holds `-1 - n`
+fn negative<'a>(n: u64, width: usize) -> Option<UnpackedRef<'a>> {
+    let value = -1 - i128::from(n);
+    if width <= 1 {
+        if let Ok(a) = i8::try_from(value) {
+            return Some(UnpackedRef::Int8(a));
+        }
+    }
+    if width <= 2 {
+        if let Ok(a) = i16::try_from(value) {
+            return Some(UnpackedRef::Int16(a));
+        }
+    }
+    if width <= 4 {
+        if let Ok(a) = i32::try_from(value) {
+            return Some(UnpackedRef::Int32(a));
+        }
+    }
+    i64::try_from(value).ok().map(UnpackedRef::Int64)
+}
+
+impl<'a> Unpacker<'a> {
+    /// Unpacks a single CBOR scalar value, or the header of an array or map
+    pub(crate) fn unpack_cbor_token_body(&mut self, type_: u8) -> Result<Token<'a>> {
+        let mut type_ = type_;
+        while type_ >> 5 == TAG {
+            let (tag, _) = self.unpack_argument(type_ & 0x1f)?;
+            if self.options().strict {
+                return Err(ErrorKind::Tag(tag).into());
+            }
+            self.warn(WarningKind::SkippedTag(tag));
+            type_ = self.unpack_uint8()?;
+        }
+
+        let (major, info) = (type_ >> 5, type_ & 0x1f);
+        if major == SIMPLE {
+            return Ok(Token::Value(self.unpack_simple(info)?));
+        }
+        if info == INDEFINITE {
+            return Err(ErrorKind::IndefiniteLength.into());
+        }
+
+        let (n, width) = self.unpack_argument(info)?;
+        if major >= BYTES {
+            self.check_minimal(width == argument_width(n))?;
+        }
+        let size = usize::try_from(n).unwrap_or(usize::MAX);
+        Ok(Token::Value(match major {
+            UNSIGNED => match width {
+                0 | 1 => UnpackedRef::Uint8(n as u8),
+                2 => UnpackedRef::Uint16(n as u16),
+                4 => UnpackedRef::Uint32(n as u32),
+                _ => UnpackedRef::Uint64(n),
+            },
+            NEGATIVE => match negative(n, width) {
+                Some(value) => value,
+                None => return Err(ErrorKind::NoEquivalent.into()),
+            },
+            BYTES => UnpackedRef::Raw(self.unpack_raw(size)?),
+            TEXT => self.unpack_string(size)?,
+            ARRAY => return Ok(Token::Array(size)),
+            _ => return Ok(Token::Map(size)),
+        }))
+    }
+
+    /// Unpacks the argument that follows an initial byte, and the number of bytes it took up
+    fn unpack_argument(&mut self, info: u8) -> Result<(u64, usize)> {
+        Ok(match info {
+            0..=23 => (info.into(), 0),
+            24 => (self.unpack_uint8()?.into(), 1),
+            25 => (self.unpack_uint16()?.into(), 2),
+            26 => (self.unpack_uint32()?.into(), 4),
+            27 => (self.unpack_uint64()?, 8),
+            _ => return Err(ErrorKind::ReservedType.into()),
+        })
+    }
+
+    /// Unpacks a simple value or float
+    fn unpack_simple(&mut self, info: u8) -> Result<UnpackedRef<'a>> {
+        Ok(match info {
+            FALSE => UnpackedRef::Bool(false),
+            TRUE => UnpackedRef::Bool(true),
+            NULL => UnpackedRef::Null,
+            UNDEFINED => UnpackedRef::Undefined,
+            FLOAT16 => UnpackedRef::Float(f16_to_f32(self.unpack_uint16()?)),
+            FLOAT32 => UnpackedRef::Float(self.unpack_float()?),
+            FLOAT64 => UnpackedRef::Double(self.unpack_double()?),
+            0..=19 | SIMPLE8 => {
+                if info == SIMPLE8 {
+                    self.unpack_uint8()?;
+                }
+                if self.options().strict {
+                    return Err(ErrorKind::ReservedType.into());
+                }
+                UnpackedRef::Undefined
+            }
+            INDEFINITE => return Err(ErrorKind::IndefiniteLength.into()),
+            _ => return Err(ErrorKind::ReservedType.into()),
+        })
+    }
+}
+
+/// Writes an initial byte and an argument `width` bytes wide
+fn pack_head<W: Write>(packed: &mut W, major: u8, n: u64, width: usize) -> Result<()> {
+    let major = major << 5;
+    match width {
+        0 => packed.write_all(&[major | n as u8])?,
+        1 => packed.write_all(&[major | 24, n as u8])?,
+        2 => {
+            packed.write_all(&[major | 25])?;
+            packed.write_all(&(n as u16).to_be_bytes())?;
+        }
+        4 => {
+            packed.write_all(&[major | 26])?;
+            packed.write_all(&(n as u32).to_be_bytes())?;
+        }
+        _ => {
+            packed.write_all(&[major | 27])?;
+            packed.write_all(&n.to_be_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes a length in the shortest form that holds it
+fn pack_len<W: Write>(packed: &mut W, major: u8, size: usize) -> Result<()> {
+    let n = size as u64;
+    pack_head(packed, major, n, argument_width(n))
+}
+
+/// Writes an integer with an argument `width` bytes wide, or in the initial byte if it is 8
+/// bits wide and fits there
+fn pack_integer<W: Write>(packed: &mut W, n: i128, width: usize) -> Result<()> {
+    let (major, n) = if n < 0 {
+        (NEGATIVE, (-1 - n) as u64)
+    } else {
+        (UNSIGNED, n as u64)
+    };
+    let width = if width == 1 && n < 24 { 0 } else { width };
+    pack_head(packed, major, n, width)
+}
+
+/// Writes a single token as CBOR, writing only the header of an array or map
+pub(crate) fn pack_token<W: Write>(packed: &mut W, token: &Token) -> Result<()> {
+    let value = match token {
+        Token::Array(size) => return pack_len(packed, ARRAY, *size),
+        Token::Map(size) => return pack_len(packed, MAP, *size),
+        Token::Value(value) => value,
+        Token::Extension(type_) => return Err(ErrorKind::Extension(*type_).into()),
+    };
+
+    let simple = SIMPLE << 5;
+    match value {
+        UnpackedRef::Uint8(a) => pack_integer(packed, (*a).into(), 1)?,
+        UnpackedRef::Uint16(a) => pack_integer(packed, (*a).into(), 2)?,
+        UnpackedRef::Uint32(a) => pack_integer(packed, (*a).into(), 4)?,
+        UnpackedRef::Uint64(a) => pack_integer(packed, (*a).into(), 8)?,
+        UnpackedRef::Int8(a) => pack_integer(packed, (*a).into(), 1)?,
+        UnpackedRef::Int16(a) => pack_integer(packed, (*a).into(), 2)?,
+        UnpackedRef::Int32(a) => pack_integer(packed, (*a).into(), 4)?,
+        UnpackedRef::Int64(a) => pack_integer(packed, (*a).into(), 8)?,
+        UnpackedRef::Float(f) => {
+            packed.write_all(&[simple | FLOAT32])?;
+            packed.write_all(&f.to_be_bytes())?;
+        }
+        UnpackedRef::Double(f) => {
+            packed.write_all(&[simple | FLOAT64])?;
+            packed.write_all(&f.to_be_bytes())?;
+        }
+        UnpackedRef::Bool(false) => packed.write_all(&[simple | FALSE])?,
+        UnpackedRef::Bool(true) => packed.write_all(&[simple | TRUE])?,
+        UnpackedRef::Null => packed.write_all(&[simple | NULL])?,
+        UnpackedRef::Undefined => packed.write_all(&[simple | UNDEFINED])?,
+        UnpackedRef::Raw(bytes) => {
+            pack_len(packed, BYTES, bytes.len())?;
+            packed.write_all(bytes)?;
+        }
+        UnpackedRef::String(s) => {
+            pack_len(packed, TEXT, s.len())?;
+            packed.write_all(s.as_bytes())?;
+        }
+        UnpackedRef::Array(_) | UnpackedRef::Map(_) => {
+            return Err(ErrorKind::Message("unexpected container in a token".to_string()).into())
+        }
+    }
+    Ok(())
+}
+
+/// Writes a value as CBOR to `writer`
+pub fn pack_to<W: Write>(writer: &mut W, value: &Unpacked) -> Result<()> {
+    value.visit_tokens(&mut |token| pack_token(writer, token))
+}
+
+/// Packs a value as CBOR
+pub fn pack(value: &Unpacked) -> Vec<u8> {
+    let mut packed = vec![];
+    match pack_to(&mut packed, value) {
+        Ok(()) => packed,
+        Err(e) => panic!("cannot pack value: {}", e),
+    }
+}
+
+/// Creates an unpacker that reads CBOR values from the start of `data` using `options`
+pub fn unpacker(data: &[u8], options: UnpackOptions) -> Unpacker<'_> {
+    Unpacker::with_options(data, options).with_format(Format::Cbor)
+}
+
+/// Unpacks data in the CBOR format
+pub fn unpack(data: &[u8]) -> Result<Unpacked> {
+    unpacker(data, UnpackOptions::default()).unpack()
+}
+
+/// Unpacks data in the CBOR format, borrowing strings and raw bytes from `data`
+pub fn unpack_ref(data: &[u8]) -> Result<UnpackedRef<'_>> {
+    unpacker(data, UnpackOptions::default()).unpack_ref()
+}
+
+/// Unpacks data in the CBOR format using `options`
+///
+/// In strict mode this fails if any bytes follow the value.
+pub fn unpack_with_options(data: &[u8], options: &UnpackOptions) -> Result<Unpacked> {
+    let mut unpacker = unpacker(data, options.clone());
+    let value = unpacker.unpack()?;
+    unpacker.check_end()?;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::PathSegment;
+
+    #[test]
+    fn pack_forms() {
+        let cases: Vec<(Unpacked, Vec<u8>)> = vec![
+            (Unpacked::Uint8(5), vec![0x05]),
+            (Unpacked::Uint8(200), vec![0x18, 200]),
+            (Unpacked::Uint16(5), vec![0x19, 0, 5]),
+            (Unpacked::Uint64(1), vec![0x1b, 0, 0, 0, 0, 0, 0, 0, 1]),
+            (Unpacked::Int8(-1), vec![0x20]),
+            (Unpacked::Int8(-128), vec![0x38, 127]),
+            (Unpacked::Int32(-1), vec![0x3a, 0, 0, 0, 0]),
+            (Unpacked::Float(1.5), vec![0xfa, 0x3f, 0xc0, 0, 0]),
+            (
+                Unpacked::Double(1.5),
+                vec![0xfb, 0x3f, 0xf8, 0, 0, 0, 0, 0, 0],
+            ),
+            (Unpacked::Undefined, vec![0xf7]),
+            (Unpacked::Null, vec![0xf6]),
+            (Unpacked::Bool(true), vec![0xf5]),
+            (Unpacked::Raw(vec![1, 2]), vec![0x42, 1, 2]),
+            ("a".into(), vec![0x61, b'a']),
+            (binarypack!([1u8, [2u8]]), vec![0x82, 0x01, 0x81, 0x02]),
+            (binarypack!({ "a": null }), vec![0xa1, 0x61, b'a', 0xf6]),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(pack(&value), expected, "{:?}", value);
+        }
+
+        let mut long = vec![0x78, 30];
+        long.extend_from_slice(&[b'x'; 30]);
+        assert_eq!(pack(&Unpacked::String("x".repeat(30))), long);
+    }
+
+    #[test]
+    fn round_trip() {
+        let value = binarypack!({
+            "ints": [
+                0u8, 255u8, 65535u16, Unpacked::Uint32(7), u64::MAX,
+                -1i8, -128i8, -129i16, Unpacked::Int32(-5), i64::MIN
+            ],
+            "floats": [Unpacked::Float(0.25), 2.5],
+            "raw": b"\x00\x01",
+            "nested": [[], {}, { 1u8: [null, true, false] }]
+        });
+        assert_eq!(unpack(&pack(&value)).unwrap(), value);
+
+        match unpack(&pack(&binarypack!([undefined]))).unwrap() {
+            Unpacked::Array(v) => assert!(matches!(v[..], [Unpacked::Undefined])),
+            value => panic!("{:?}", value),
+        }
+        assert_eq!(
+            unpack(&pack(&Unpacked::Int16(5))).unwrap(),
+            Unpacked::Uint16(5)
+        );
+    }
+
+    #[test]
+    fn unpack_forms() {
+        let cases: Vec<(Vec<u8>, Unpacked)> = vec![
+            (vec![0xf9, 0x3c, 0x00], Unpacked::Float(1.0)),
+            (vec![0xf9, 0xc4, 0x00], Unpacked::Float(-4.0)),
+            (vec![0xf9, 0x7c, 0x00], Unpacked::Float(f32::INFINITY)),
+            (vec![0xf9, 0x00, 0x01], Unpacked::Float(2f32.powi(-24))),
+            (vec![0x39, 0x7f, 0xff], Unpacked::Int16(-32768)),
+            (vec![0x39, 0x80, 0x00], Unpacked::Int32(-32769)),
+            (vec![0x38, 0xff], Unpacked::Int16(-256)),
+        ];
+        for (data, expected) in cases {
+            assert_eq!(unpack(&data).unwrap(), expected, "{:02x?}", data);
+        }
+        assert!(unpack(&[0xf9, 0x7e, 0x00])
+            .unwrap()
+            .as_f64()
+            .unwrap()
+            .is_nan());
+
+        let e = unpack(&[0x81, 0x3b, 0x80, 0, 0, 0, 0, 0, 0, 0]).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::NoEquivalent));
+        assert_eq!(e.offset(), Some(1));
+        assert_eq!(e.path(), &[PathSegment::Index(0)]);
+
+        let e = unpack(&[0x9f, 0xff]).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::IndefiniteLength));
+        let e = unpack(&[0x1c]).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::ReservedType));
+    }
+
+    #[test]
+    fn tags() {
+        let data = [0x82, 0xc1, 0x1a, 0, 0, 0, 1, 0xd8, 0x20, 0x61, b'x'];
+        let mut unpacker = unpacker(&data, UnpackOptions::default());
+        assert_eq!(
+            unpacker.unpack().unwrap(),
+            binarypack!([Unpacked::Uint32(1), "x"])
+        );
+        let warnings = unpacker.take_warnings();
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(warnings[0].kind(), &WarningKind::SkippedTag(1));
+        assert_eq!(warnings[1].kind(), &WarningKind::SkippedTag(32));
+        assert_eq!(warnings[1].offset(), 7);
+    }
+
+    #[test]
+    fn strict() {
+        let options = UnpackOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let e = unpack_with_options(&[0xc1, 0x01], &options).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::Tag(1)));
+
+        let e = unpack_with_options(&[0xf0], &options).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::ReservedType));
+        assert!(matches!(
+            unpack(&[0xf8, 0x20]).unwrap(),
+            Unpacked::Undefined
+        ));
+
+        let e = unpack_with_options(&[0x78, 1, b'a'], &options).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::NonMinimalEncoding));
+        assert_eq!(unpack(&[0x78, 1, b'a']).unwrap(), "a".into());
+        unpack_with_options(&[0x19, 0, 1], &options).unwrap();
+
+        let e = unpack_with_options(&[0x01, 0x01], &options).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::TrailingData));
+    }
+}
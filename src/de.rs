@@ -0,0 +1,677 @@
+//! Deserialize Rust data structures from the js-binarypack format using serde
+//!
+//! This reads the mapping described in `ser` back, and is lenient where the wire format allows
+//! several encodings of the same data:
+//!
+//! * any integer width (`Uint8` through `Int64`) is accepted for any numeric field, as long as
+//!   the value is in range
+//! * both `Null` and `Undefined` deserialize as `None` or `()`; to self-describing visitors
+//!   `Null` is reported as `none` and `Undefined` as `unit`
+//! * `&str` and `&[u8]` fields borrow from the input instead of copying it
+//!
+//! Bytes following the first value are ignored, just as they are by `binarypack::unpack`, unless
+//! `from_slice_with_options` is used in strict mode.
+
+use serde::de::{self, Deserialize, DeserializeSeed, IntoDeserializer, Visitor};
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::binarypack::{Token, UnpackOptions, Unpacked, UnpackedRef, Unpacker};
+use crate::error::{Error, Result, Warning};
+use crate::ser::UNDEFINED;
+
+/// A serde `Deserializer` that reads the js-binarypack format from a byte slice
+pub struct Deserializer<'de> {
+    unpacker: Unpacker<'de>,
+}
+
+impl<'de> Deserializer<'de> {
+    pub fn from_slice(data: &'de [u8]) -> Deserializer<'de> {
+        Deserializer::with_options(data, UnpackOptions::default())
+    }
+
+    pub fn with_options(data: &'de [u8], options: UnpackOptions) -> Deserializer<'de> {
+        Deserializer {
+            unpacker: Unpacker::with_options(data, options),
+        }
+    }
+
+    /// Runs `f` to deserialize the contents of a container one level deeper than the current one
+    fn descend<T, F: FnOnce(&mut Self) -> Result<T>>(&mut self, f: F) -> Result<T> {
+        self.unpacker.enter();
+        let result = f(self);
+        self.unpacker.leave();
+        result
+    }
+
+    /// Hands `token`, which has just been read, to `visitor`
+    fn visit_token<V: Visitor<'de>>(&mut self, token: Token<'de>, visitor: V) -> Result<V::Value> {
+        match token {
+            Token::Value(value) => match value {
+                UnpackedRef::Uint8(a) => visitor.visit_u8(a),
+                UnpackedRef::Uint16(a) => visitor.visit_u16(a),
+                UnpackedRef::Uint32(a) => visitor.visit_u32(a),
+                UnpackedRef::Uint64(a) => visitor.visit_u64(a),
+                UnpackedRef::Int8(a) => visitor.visit_i8(a),
+                UnpackedRef::Int16(a) => visitor.visit_i16(a),
+                UnpackedRef::Int32(a) => visitor.visit_i32(a),
+                UnpackedRef::Int64(a) => visitor.visit_i64(a),
+                UnpackedRef::Float(f) => visitor.visit_f32(f),
+                UnpackedRef::Double(f) => visitor.visit_f64(f),
+                UnpackedRef::Bool(b) => visitor.visit_bool(b),
+                UnpackedRef::Raw(bytes) => visitor.visit_borrowed_bytes(bytes),
+                UnpackedRef::String(Cow::Borrowed(s)) => visitor.visit_borrowed_str(s),
+                UnpackedRef::String(Cow::Owned(s)) => visitor.visit_string(s),
+                UnpackedRef::Null => visitor.visit_none(),
+                UnpackedRef::Undefined => visitor.visit_unit(),
+                UnpackedRef::Array(_) | UnpackedRef::Map(_) => {
+                    Err(de::Error::custom("unexpected container in a token"))
+                }
+            },
+            Token::Extension(_) => visitor.visit_unit(),
+            Token::Array(len) => self.descend(|de| {
+                let mut access = Access { de, remaining: len };
+                let value = visitor.visit_seq(&mut access)?;
+                access.end(len)?;
+                Ok(value)
+            }),
+            Token::Map(len) => self.descend(|de| {
+                let mut access = Access { de, remaining: len };
+                let value = visitor.visit_map(&mut access)?;
+                access.end(len)?;
+                Ok(value)
+            }),
+        }
+    }
+
+    /// The problems repaired in the input so far, as set by `UnpackOptions::invalid_utf8` and
+    /// `UnpackOptions::js_numbers`
+    pub fn warnings(&self) -> &[Warning] {
+        self.unpacker.warnings()
+    }
+
+    /// The bytes of the input that have not been deserialized yet
+    pub fn remaining(&self) -> &'de [u8] {
+        self.unpacker.remaining()
+    }
+}
+
+/// Deserializes a value of type `T` from the js-binarypack format
+///
+/// Errors raised by `T`'s `Deserialize` implementation, rather than by malformed input, are
+/// reported at the offset the deserializer had reached when they occurred.
+pub fn from_slice<'a, T: Deserialize<'a>>(data: &'a [u8]) -> Result<T> {
+    from_slice_with_options(data, UnpackOptions::default())
+}
+
+/// Deserializes a value of type `T` using `options`
+///
+/// In strict mode this fails if any bytes follow the value.
+pub fn from_slice_with_options<'a, T: Deserialize<'a>>(
+    data: &'a [u8],
+    options: UnpackOptions,
+) -> Result<T> {
+    let mut de = Deserializer::with_options(data, options);
+    let value = T::deserialize(&mut de).map_err(|e| e.at(de.unpacker.offset(), None))?;
+    de.unpacker.check_end()?;
+    Ok(value)
+}
+
+/// Visits the elements of an array, or the entries of a map
+struct Access<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+    remaining: usize,
+}
+
+impl<'a, 'de> Access<'a, 'de> {
+    /// Fails unless the visitor consumed every element of the container
+    fn end(&self, len: usize) -> Result<()> {
+        if self.remaining == 0 {
+            Ok(())
+        } else {
+            Err(de::Error::invalid_length(len, &"fewer elements"))
+        }
+    }
+}
+
+impl<'a, 'de> de::SeqAccess<'de> for Access<'a, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+impl<'a, 'de> de::MapAccess<'de> for Access<'a, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        self.de.unpacker.expect_key();
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+/// Visits an enum encoded as a single entry map from variant name to contents
+struct Enum<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+}
+
+impl<'a, 'de> de::EnumAccess<'de> for Enum<'a, 'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self)> {
+        self.de.unpacker.expect_key();
+        let variant = seed.deserialize(&mut *self.de)?;
+        Ok((variant, self))
+    }
+}
+
+impl<'a, 'de> de::VariantAccess<'de> for Enum<'a, 'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        de::Deserialize::deserialize(self.de)
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        seed.deserialize(self.de)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        de::Deserializer::deserialize_seq(self.de, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        de::Deserializer::deserialize_map(self.de, visitor)
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let token = self.unpacker.unpack_token()?;
+        self.visit_token(token, visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if self.unpacker.unpack_nil()? {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if self.unpacker.unpack_nil()? {
+            visitor.visit_unit()
+        } else {
+            self.deserialize_any(visitor)
+        }
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        if name != UNDEFINED {
+            return visitor.visit_newtype_struct(self);
+        }
+        // Only `Unpacked` asks for this name, and it is told about `Undefined` as a unit variant
+        // of the same name instead of as `unit`, which it takes to be `Null`
+        match self.unpacker.unpack_token()? {
+            Token::Value(UnpackedRef::Undefined) | Token::Extension(_) => {
+                visitor.visit_enum(UNDEFINED.into_deserializer())
+            }
+            token => self.visit_token(token, visitor),
+        }
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        match self.unpacker.unpack_token()? {
+            Token::Value(UnpackedRef::String(variant)) => {
+                visitor.visit_enum(variant.into_deserializer())
+            }
+            Token::Map(1) => self.descend(|de| visitor.visit_enum(Enum { de })),
+            _ => Err(de::Error::custom(
+                "expected a variant name or a single entry map",
+            )),
+        }
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+/// Builds an `Unpacked` from any self-describing serde format
+///
+/// This is the reverse of the mapping used by `Unpacked`'s `Serialize` implementation. Integers
+/// keep the width the format reports them with, so a JSON number becomes a `Uint64` or `Int64`.
+/// Both `None` and `()` become `Null`, so that JSON's null, which is reported as `()`, reads as
+/// `Null`. `Undefined` is only produced when the format spells it out: this crate's
+/// `Deserializer` reports it, and any extension type, as a unit variant with a reserved name.
+struct UnpackedVisitor;
+
+impl<'de> Visitor<'de> for UnpackedVisitor {
+    type Value = Unpacked;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "any binarypack value")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> std::result::Result<Unpacked, E> {
+        Ok(Unpacked::Bool(v))
+    }
+
+    fn visit_i8<E>(self, v: i8) -> std::result::Result<Unpacked, E> {
+        Ok(Unpacked::Int8(v))
+    }
+
+    fn visit_i16<E>(self, v: i16) -> std::result::Result<Unpacked, E> {
+        Ok(Unpacked::Int16(v))
+    }
+
+    fn visit_i32<E>(self, v: i32) -> std::result::Result<Unpacked, E> {
+        Ok(Unpacked::Int32(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> std::result::Result<Unpacked, E> {
+        Ok(Unpacked::Int64(v))
+    }
+
+    fn visit_u8<E>(self, v: u8) -> std::result::Result<Unpacked, E> {
+        Ok(Unpacked::Uint8(v))
+    }
+
+    fn visit_u16<E>(self, v: u16) -> std::result::Result<Unpacked, E> {
+        Ok(Unpacked::Uint16(v))
+    }
+
+    fn visit_u32<E>(self, v: u32) -> std::result::Result<Unpacked, E> {
+        Ok(Unpacked::Uint32(v))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> std::result::Result<Unpacked, E> {
+        Ok(Unpacked::Uint64(v))
+    }
+
+    fn visit_f32<E>(self, v: f32) -> std::result::Result<Unpacked, E> {
+        Ok(Unpacked::Float(v))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> std::result::Result<Unpacked, E> {
+        Ok(Unpacked::Double(v))
+    }
+
+    fn visit_str<E>(self, v: &str) -> std::result::Result<Unpacked, E> {
+        Ok(Unpacked::String(v.to_string()))
+    }
+
+    fn visit_string<E>(self, v: String) -> std::result::Result<Unpacked, E> {
+        Ok(Unpacked::String(v))
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> std::result::Result<Unpacked, E> {
+        Ok(Unpacked::Raw(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> std::result::Result<Unpacked, E> {
+        Ok(Unpacked::Raw(v))
+    }
+
+    fn visit_none<E>(self) -> std::result::Result<Unpacked, E> {
+        Ok(Unpacked::Null)
+    }
+
+    fn visit_some<D: de::Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<Unpacked, D::Error> {
+        Unpacked::deserialize(deserializer)
+    }
+
+    fn visit_unit<E>(self) -> std::result::Result<Unpacked, E> {
+        Ok(Unpacked::Null)
+    }
+
+    fn visit_newtype_struct<D: de::Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<Unpacked, D::Error> {
+        deserializer.deserialize_any(UnpackedVisitor)
+    }
+
+    fn visit_enum<A: de::EnumAccess<'de>>(
+        self,
+        data: A,
+    ) -> std::result::Result<Unpacked, A::Error> {
+        let (name, variant): (String, _) = data.variant()?;
+        if name != UNDEFINED {
+            return Err(de::Error::invalid_value(de::Unexpected::Str(&name), &self));
+        }
+        de::VariantAccess::unit_variant(variant)?;
+        Ok(Unpacked::Undefined)
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(
+        self,
+        mut seq: A,
+    ) -> std::result::Result<Unpacked, A::Error> {
+        let mut arr = vec![];
+        while let Some(element) = seq.next_element()? {
+            arr.push(element);
+        }
+        Ok(Unpacked::Array(arr))
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(
+        self,
+        mut map: A,
+    ) -> std::result::Result<Unpacked, A::Error> {
+        let mut m = HashMap::new();
+        while let Some((key, value)) = map.next_entry()? {
+            m.insert(key, value);
+        }
+        Ok(Unpacked::Map(m))
+    }
+}
+
+impl<'de> Deserialize<'de> for Unpacked {
+    fn deserialize<D: de::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Unpacked, D::Error> {
+        // Binary formats pass newtype structs through untouched, which lets this crate's
+        // `Deserializer` see the name and report `Undefined`
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(UnpackedVisitor)
+        } else {
+            deserializer.deserialize_newtype_struct(UNDEFINED, UnpackedVisitor)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::binarypack::Unpacked;
+    use crate::error::ErrorKind;
+    use crate::ser::to_vec;
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum E {
+        Unit,
+        Newtype(u8),
+        Tuple(u8, i16),
+        Struct { a: u8 },
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Message {
+        id: u64,
+        score: f64,
+        name: String,
+        tags: Vec<String>,
+        parent: Option<u32>,
+        kinds: Vec<E>,
+        pair: (i8, bool),
+    }
+
+    #[test]
+    fn round_trip() {
+        let message = Message {
+            id: 12,
+            score: 0.5,
+            name: "hello".to_string(),
+            tags: vec!["a".to_string(), "b".to_string()],
+            parent: Some(3),
+            kinds: vec![E::Unit, E::Newtype(1), E::Tuple(2, -3), E::Struct { a: 4 }],
+            pair: (-1, true),
+        };
+        let packed = to_vec(&message).unwrap();
+        assert_eq!(from_slice::<Message>(&packed).unwrap(), message);
+
+        let message = Message {
+            parent: None,
+            kinds: vec![],
+            ..message
+        };
+        let packed = to_vec(&message).unwrap();
+        assert_eq!(from_slice::<Message>(&packed).unwrap(), message);
+    }
+
+    #[test]
+    fn any_integer_width() {
+        assert_eq!(from_slice::<u64>(&Unpacked::Uint8(5).pack()).unwrap(), 5);
+        assert_eq!(from_slice::<u8>(&Unpacked::Int64(5).pack()).unwrap(), 5);
+        assert_eq!(from_slice::<i16>(&Unpacked::Int8(-5).pack()).unwrap(), -5);
+        assert_eq!(from_slice::<f64>(&Unpacked::Uint32(7).pack()).unwrap(), 7.0);
+        assert!(from_slice::<u8>(&Unpacked::Uint16(256).pack()).is_err());
+        assert!(from_slice::<u32>(&Unpacked::Int8(-1).pack()).is_err());
+    }
+
+    #[test]
+    fn null_and_undefined() {
+        assert_eq!(from_slice::<Option<u8>>(&[0xc0]).unwrap(), None);
+        assert_eq!(from_slice::<Option<u8>>(&[0xc1]).unwrap(), None);
+        assert_eq!(from_slice::<Option<u8>>(&[1]).unwrap(), Some(1));
+        from_slice::<()>(&[0xc1]).unwrap();
+    }
+
+    #[test]
+    fn borrowed() {
+        #[derive(Deserialize)]
+        struct Borrowed<'a> {
+            name: &'a str,
+            data: &'a [u8],
+        }
+
+        let mut m = HashMap::new();
+        m.insert(
+            Unpacked::String("name".to_string()),
+            Unpacked::String("abc".to_string()),
+        );
+        m.insert(
+            Unpacked::String("data".to_string()),
+            Unpacked::Raw(vec![1, 2, 3]),
+        );
+        let packed = Unpacked::Map(m).pack();
+        let borrowed: Borrowed = from_slice(&packed).unwrap();
+        assert_eq!(borrowed.name, "abc");
+        assert_eq!(borrowed.data, &[1, 2, 3]);
+
+        let range = packed.as_ptr_range();
+        assert!(range.contains(&borrowed.name.as_ptr()));
+        assert!(range.contains(&borrowed.data.as_ptr()));
+    }
+
+    #[test]
+    fn unpacked() {
+        let mut m = HashMap::new();
+        m.insert(
+            Unpacked::String("a".to_string()),
+            Unpacked::Array(vec![
+                Unpacked::Uint8(1),
+                Unpacked::Uint16(2),
+                Unpacked::Uint32(3),
+                Unpacked::Uint64(4),
+                Unpacked::Int8(-1),
+                Unpacked::Int16(-2),
+                Unpacked::Int32(-3),
+                Unpacked::Int64(-4),
+                Unpacked::Float(0.5),
+                Unpacked::Double(0.25),
+                Unpacked::Bool(true),
+                Unpacked::Raw(vec![1, 2]),
+                Unpacked::Null,
+            ]),
+        );
+        m.insert(Unpacked::Uint8(1), Unpacked::Map(HashMap::new()));
+        let value = Unpacked::Map(m);
+
+        let packed = value.pack();
+        let unpacked: Unpacked = from_slice(&packed).unwrap();
+        assert_eq!(unpacked, value);
+
+        let unpacked: Unpacked = from_slice(&Unpacked::Undefined.pack()).unwrap();
+        assert!(matches!(unpacked, Unpacked::Undefined));
+
+        let unpacked: Unpacked = serde_json::from_str(r#"{"a": [1, -1, 0.5, [1]]}"#).unwrap();
+        let mut m = HashMap::new();
+        m.insert(
+            Unpacked::String("a".to_string()),
+            Unpacked::Array(vec![
+                Unpacked::Uint64(1),
+                Unpacked::Int64(-1),
+                Unpacked::Double(0.5),
+                Unpacked::Array(vec![Unpacked::Uint64(1)]),
+            ]),
+        );
+        assert_eq!(unpacked, Unpacked::Map(m));
+
+        let packed = Unpacked::Array(vec![Unpacked::Null, Unpacked::Undefined]).pack();
+        let unpacked: Unpacked = from_slice(&packed).unwrap();
+        assert_eq!(unpacked.pack(), packed);
+    }
+
+    #[test]
+    fn json_null() {
+        let unpacked: Unpacked = serde_json::from_str(r#"{"a": null}"#).unwrap();
+        assert_eq!(unpacked, binarypack!({"a": null}));
+        assert_eq!(unpacked.pack(), binarypack!({"a": null}).pack());
+        assert!(!unpacked.pack().contains(&0xc1));
+
+        let json = serde_json::to_string(&unpacked).unwrap();
+        assert_eq!(json, r#"{"a":null}"#);
+        let unpacked: Unpacked = serde_json::from_str(&json).unwrap();
+        assert_eq!(unpacked, binarypack!({"a": null}));
+
+        let unpacked: Unpacked = serde_json::from_str(r#"[null, 1]"#).unwrap();
+        assert_eq!(
+            unpacked,
+            Unpacked::Array(vec![Unpacked::Null, Unpacked::Uint64(1)])
+        );
+    }
+
+    #[test]
+    fn max_depth() {
+        let mut packed = vec![0x91; 200];
+        packed.push(0xc0);
+        let e = from_slice::<Unpacked>(&packed).unwrap_err();
+        assert!(matches!(
+            e.kind(),
+            crate::error::ErrorKind::DepthLimitExceeded(128)
+        ));
+
+        let options = UnpackOptions {
+            max_depth: 200,
+            ..Default::default()
+        };
+        let mut de = Deserializer::with_options(&packed, options);
+        assert!(Unpacked::deserialize(&mut de).is_ok());
+    }
+
+    #[test]
+    fn errors() {
+        assert!(from_slice::<String>(&[0xd8, 0, 5])
+            .unwrap_err()
+            .is_end_of_data());
+        assert!(from_slice::<(u8, u8)>(&[0x93, 1, 2, 3]).is_err());
+        let e = from_slice::<(u8, bool)>(&[0x92, 1, 2]).unwrap_err();
+        assert_eq!(e.offset(), Some(3));
+        assert!(from_slice::<E>(&[0x82, 0xa0, 0xc0, 0xa1, 1, 0xc0]).is_err());
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        use crate::binarypack::InvalidUtf8;
+
+        let packed = [0xd8, 0, 2, 0x61, 0x80];
+        assert!(from_slice::<String>(&packed).is_err());
+
+        let options = UnpackOptions {
+            invalid_utf8: InvalidUtf8::Replace,
+            ..Default::default()
+        };
+        let mut de = Deserializer::with_options(&packed, options);
+        assert_eq!(String::deserialize(&mut de).unwrap(), "a\u{fffd}");
+        assert_eq!(de.warnings().len(), 1);
+
+        let options = UnpackOptions {
+            invalid_utf8: InvalidUtf8::Raw,
+            ..Default::default()
+        };
+        let bytes: &[u8] = from_slice_with_options(&packed, options).unwrap();
+        assert_eq!(bytes, &packed[3..]);
+    }
+
+    #[test]
+    fn strict() {
+        let strict = UnpackOptions {
+            strict: true,
+            ..Default::default()
+        };
+        assert_eq!(from_slice::<u8>(&[1, 2]).unwrap(), 1);
+        let e = from_slice_with_options::<u8>(&[1, 2], strict.clone()).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::TrailingData));
+        assert_eq!(e.offset(), Some(1));
+
+        let packed = [0x81, 0x01, 0x02];
+        let map: HashMap<u8, u8> = from_slice(&packed).unwrap();
+        assert_eq!(map[&1], 2);
+        let e = from_slice_with_options::<HashMap<u8, u8>>(&packed, strict.clone()).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::NonStringKey));
+        let e = from_slice_with_options::<HashMap<Option<u8>, u8>>(&[0x81, 0xc0, 0x02], strict)
+            .unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::NonStringKey));
+    }
+}
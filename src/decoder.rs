@@ -0,0 +1,370 @@
+use crate::binarypack::{Frame, UnpackOptions, Unpacked, Unpacker, Usage};
+use crate::error::{Error, Result, Warning};
+
+/// The outcome of a call to `Decoder::decode`
+#[derive(Debug, PartialEq)]
+pub enum Status {
+    /// A complete value was decoded
+    Complete(Unpacked),
+    /// The buffered input ends part way through a value; feed more bytes and try again
+    NeedMore,
+}
+
+/// A push-style decoder for input that arrives in fragments
+///
+/// Bytes are handed to the decoder with `feed` as they are received. `decode` returns
+/// `Status::NeedMore` instead of failing when the input runs out, and keeps the arrays and maps
+/// it has already started so that decoding resumes where it stopped once more bytes arrive.
+pub struct Decoder {
+    buffer: Vec<u8>,
+    pos: usize,
+    // The number of bytes dropped from the front of `buffer` since the decoder was created
+    consumed: usize,
+    stack: Vec<Frame<Unpacked>>,
+    options: UnpackOptions,
+    // How much of its budget the value being decoded has used
+    usage: Usage,
+    warnings: Vec<Warning>,
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder::with_options(UnpackOptions::default())
+    }
+
+    pub fn with_options(options: UnpackOptions) -> Decoder {
+        Decoder {
+            buffer: vec![],
+            pos: 0,
+            consumed: 0,
+            stack: vec![],
+            options,
+            usage: Usage::default(),
+            warnings: vec![],
+        }
+    }
+
+    /// Appends newly received bytes to the input
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buffer.drain(..self.pos);
+        self.consumed += self.pos;
+        self.pos = 0;
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// The number of bytes fed to the decoder that have not been decoded yet
+    pub fn buffered(&self) -> usize {
+        self.buffer.len() - self.pos
+    }
+
+    /// The total number of bytes decoded since the decoder was created or last reset
+    pub fn offset(&self) -> usize {
+        self.consumed + self.pos
+    }
+
+    /// Returns true if a value has been partially decoded or undecoded input is buffered
+    pub fn in_progress(&self) -> bool {
+        !self.stack.is_empty() || self.buffered() > 0
+    }
+
+    /// Discards all buffered input and any partially decoded value
+    pub fn reset(&mut self) {
+        self.buffer.clear();
+        self.pos = 0;
+        self.consumed = 0;
+        self.stack.clear();
+        self.usage = Usage::default();
+        self.warnings.clear();
+    }
+
+    /// The problems repaired in the values decoded so far, as set by
+    /// `UnpackOptions::invalid_utf8` and `UnpackOptions::js_numbers`
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// Returns the warnings recorded so far, and clears them
+    pub fn take_warnings(&mut self) -> Vec<Warning> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// Decodes the next value from the buffered input
+    ///
+    /// Any error other than running out of input leaves the decoder in an unspecified state, and
+    /// it should be `reset` before being used again.
+    pub fn decode(&mut self) -> Result<Status> {
+        'tokens: loop {
+            let mut unpacker = self.unpacker();
+            if self.stack.last().is_some_and(Frame::expects_key) {
+                unpacker.expect_key();
+            }
+            let token = match unpacker.unpack_token() {
+                Ok(token) => token,
+                Err(e) if e.is_end_of_data() => return Ok(Status::NeedMore),
+                Err(e) => return Err(self.in_context(e)),
+            };
+            let pos = self.pos + unpacker.offset();
+            let usage = unpacker.usage();
+            let warnings = unpacker.take_warnings();
+            let frame = Frame::new(token);
+            let offset = self.offset();
+            self.warnings
+                .extend(warnings.into_iter().map(|w| w.shifted(offset)));
+            self.pos = pos;
+            self.usage = usage;
+
+            let mut value = match frame {
+                Ok(frame) => {
+                    self.stack.push(frame);
+                    continue;
+                }
+                Err(value) => value,
+            };
+
+            while let Some(mut frame) = self.stack.pop() {
+                if !frame.push(value) {
+                    self.stack.push(frame);
+                    continue 'tokens;
+                }
+                value = frame.finish();
+            }
+            return Ok(Status::Complete(value));
+        }
+    }
+
+    /// Signals that no more input will arrive, failing if a value was left incomplete
+    ///
+    /// This is meant to be called once `decode` has returned `Status::NeedMore`, and the error
+    /// describes the bytes that were still missing.
+    pub fn finish(&self) -> Result<()> {
+        if !self.in_progress() {
+            return Ok(());
+        }
+
+        let mut unpacker = self.unpacker();
+        let e = match unpacker.unpack_token() {
+            Err(e) => e,
+            Ok(_) => Error::end_of_data(1, 0).at(self.buffered(), None),
+        };
+        Err(self.in_context(e))
+    }
+
+    /// Creates an unpacker for the undecoded input, inside the containers that are still open
+    fn unpacker(&self) -> Unpacker<'_> {
+        let usage = if self.stack.is_empty() {
+            Usage::default()
+        } else {
+            self.usage
+        };
+        Unpacker::nested(
+            &self.buffer[self.pos..],
+            self.options.clone(),
+            self.stack.len(),
+            usage,
+        )
+    }
+
+    /// Rebases an error from the unpacker onto the whole input, and adds the path through the
+    /// containers that are still open
+    fn in_context(&self, e: Error) -> Error {
+        Frame::in_context(&self.stack, e.shifted(self.offset()))
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::{ErrorKind, PathSegment};
+    use std::collections::HashMap;
+
+    #[test]
+    fn decode_complete() {
+        let mut decoder = Decoder::new();
+        decoder.feed(&[1, 0xc0]);
+        assert_eq!(
+            decoder.decode().unwrap(),
+            Status::Complete(Unpacked::Uint8(1))
+        );
+        assert_eq!(decoder.decode().unwrap(), Status::Complete(Unpacked::Null));
+        assert_eq!(decoder.decode().unwrap(), Status::NeedMore);
+        assert!(!decoder.in_progress());
+    }
+
+    #[test]
+    fn decode_fragments() {
+        let mut m = HashMap::new();
+        m.insert(
+            Unpacked::String("A".to_string()),
+            Unpacked::Array(vec![Unpacked::Uint16(258), Unpacked::Array(vec![])]),
+        );
+        m.insert(
+            Unpacked::String("B".to_string()),
+            Unpacked::Map(HashMap::new()),
+        );
+        let expected = Unpacked::Map(m);
+        let packed = expected.pack();
+
+        let mut decoder = Decoder::new();
+        for (i, b) in packed.iter().enumerate() {
+            decoder.feed(&[*b]);
+            let status = decoder.decode().unwrap();
+            if i + 1 < packed.len() {
+                assert_eq!(status, Status::NeedMore);
+                assert!(decoder.in_progress());
+            } else {
+                assert_eq!(status, Status::Complete(expected.clone()));
+            }
+        }
+        assert!(!decoder.in_progress());
+    }
+
+    #[test]
+    fn decode_split_scalar() {
+        let mut decoder = Decoder::new();
+        decoder.feed(&[0xd8, 0, 2, 65]);
+        assert_eq!(decoder.decode().unwrap(), Status::NeedMore);
+        assert_eq!(decoder.buffered(), 4);
+        decoder.feed(&[66, 3]);
+        assert_eq!(
+            decoder.decode().unwrap(),
+            Status::Complete(Unpacked::String("AB".to_string()))
+        );
+        assert_eq!(decoder.buffered(), 1);
+
+        decoder.reset();
+        assert!(!decoder.in_progress());
+    }
+
+    #[test]
+    fn finish() {
+        let mut decoder = Decoder::new();
+        decoder.feed(&[1, 0x92, 0x81, 0xa1, 65]);
+        assert_eq!(
+            decoder.decode().unwrap(),
+            Status::Complete(Unpacked::Uint8(1))
+        );
+        assert_eq!(decoder.decode().unwrap(), Status::NeedMore);
+        decoder.feed(&[0xd8, 0, 3, 65]);
+        assert_eq!(decoder.decode().unwrap(), Status::NeedMore);
+        assert_eq!(decoder.offset(), 5);
+
+        let e = decoder.finish().unwrap_err();
+        assert!(e.is_end_of_data());
+        assert_eq!(e.offset(), Some(5));
+        assert_eq!(e.type_byte(), Some(0xd8));
+        assert_eq!(
+            e.path(),
+            &[
+                PathSegment::Index(0),
+                PathSegment::Key(Unpacked::Raw(vec![65]))
+            ]
+        );
+
+        decoder.reset();
+        decoder.finish().unwrap();
+    }
+
+    #[test]
+    fn max_depth() {
+        let mut decoder = Decoder::with_options(UnpackOptions {
+            max_depth: 2,
+            ..Default::default()
+        });
+        decoder.feed(&[0x91, 0x91]);
+        assert_eq!(decoder.decode().unwrap(), Status::NeedMore);
+        decoder.feed(&[0x91, 0xc0]);
+        let e = decoder.decode().unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::DepthLimitExceeded(2)));
+        assert_eq!(e.offset(), Some(2));
+        assert_eq!(e.path(), &[PathSegment::Index(0), PathSegment::Index(0)]);
+    }
+
+    #[test]
+    fn budgets() {
+        let options = UnpackOptions {
+            max_elements: Some(3),
+            ..Default::default()
+        };
+        let mut decoder = Decoder::with_options(options);
+        decoder.feed(&[0x92, 0x01]);
+        assert_eq!(decoder.decode().unwrap(), Status::NeedMore);
+        decoder.feed(&[0x02, 0x92]);
+        assert_eq!(
+            decoder.decode().unwrap(),
+            Status::Complete(binarypack!([1u8, 2u8]))
+        );
+        decoder.feed(&[0x01, 0x91, 0x02]);
+        let e = decoder.decode().unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::ElementLimitExceeded(3)));
+        assert_eq!(e.offset(), Some(5));
+        assert_eq!(e.path(), &[PathSegment::Index(1)]);
+
+        let options = UnpackOptions {
+            max_string_len: Some(100),
+            ..Default::default()
+        };
+        let mut decoder = Decoder::with_options(options);
+        decoder.feed(&[0xdb, 0x00, 0x01, 0x00, 0x00]);
+        let e = decoder.decode().unwrap_err();
+        assert!(matches!(
+            e.kind(),
+            ErrorKind::StringLengthLimitExceeded(100)
+        ));
+    }
+
+    #[test]
+    fn strict() {
+        let options = UnpackOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let mut decoder = Decoder::with_options(options);
+        decoder.feed(&[0x81, 0xb1, 0x61, 0x81, 0xb1]);
+        assert_eq!(decoder.decode().unwrap(), Status::NeedMore);
+        decoder.feed(&[0x62, 0x81, 0x01, 0x02]);
+        let e = decoder.decode().unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::NonStringKey));
+        assert_eq!(e.offset(), Some(7));
+        assert_eq!(e.path().len(), 3);
+    }
+
+    #[test]
+    fn warnings() {
+        let options = UnpackOptions {
+            invalid_utf8: crate::binarypack::InvalidUtf8::Replace,
+            ..Default::default()
+        };
+        let mut decoder = Decoder::with_options(options);
+        decoder.feed(&[0xc0, 0x91, 0xd8, 0, 1]);
+        assert_eq!(decoder.decode().unwrap(), Status::Complete(Unpacked::Null));
+        assert_eq!(decoder.decode().unwrap(), Status::NeedMore);
+        assert!(decoder.warnings().is_empty());
+        decoder.feed(&[0x80]);
+        assert_eq!(
+            decoder.decode().unwrap(),
+            Status::Complete(binarypack!(["\u{fffd}"]))
+        );
+        let warnings = decoder.take_warnings();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].offset(), 2);
+    }
+
+    #[test]
+    fn repeated_keys() {
+        let mut decoder = Decoder::new();
+        decoder.feed(&[0x82, 0x01, 0x02, 0x01, 0x03, 0xc0]);
+        let mut m = HashMap::new();
+        m.insert(Unpacked::Uint8(1), Unpacked::Uint8(3));
+        assert_eq!(
+            decoder.decode().unwrap(),
+            Status::Complete(Unpacked::Map(m))
+        );
+        assert_eq!(decoder.decode().unwrap(), Status::Complete(Unpacked::Null));
+    }
+}
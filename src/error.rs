@@ -1,16 +1,432 @@
+use std::error;
+use std::fmt;
+use std::io;
 use std::result;
+use std::str::Utf8Error;
 use std::string::FromUtf8Error;
 
+use crate::binarypack::Unpacked;
+
+/// The kind of failure behind an `Error`
 #[derive(Debug)]
-pub enum Error {
-    EndOfData,
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The input ended before a value was complete
+    EndOfData {
+        /// The number of bytes that were needed
+        expected: usize,
+        /// The number of bytes that were left
+        available: usize,
+    },
+    /// A string was not valid UTF-8
     StringParseError,
+    /// Arrays and maps were nested deeper than the limit set in `UnpackOptions`
+    DepthLimitExceeded(usize),
+    /// A value contained more elements than the limit set in `UnpackOptions`
+    ElementLimitExceeded(usize),
+    /// A string or raw was longer than the limit set in `UnpackOptions`
+    StringLengthLimitExceeded(usize),
+    /// A value took up more bytes than the limit set in `UnpackOptions`
+    ByteLimitExceeded(usize),
+    /// A map had more entries than the limit set in `UnpackOptions`
+    MapLengthLimitExceeded(usize),
+    /// A type byte that js-binarypack reserves was found in strict mode
+    ReservedType,
+    /// Bytes followed the value in strict mode
+    TrailingData,
+    /// A length or integer was written in a wider form than needed in strict mode
+    NonMinimalEncoding,
+    /// A map key other than a string was found in strict mode
+    NonStringKey,
+    /// A string, raw, array or map had more elements than the format can encode
+    TooLarge(usize),
+    /// An integer beyond ±(2^53 - 1), which a JavaScript number cannot hold exactly, was packed
+    /// with `PackOptions::js_numbers`
+    UnsafeInteger(i128),
+    /// A MessagePack extension value, of the type given, was found in strict mode or while
+    /// converting to another format
+    Extension(i8),
+    /// A value has no equivalent in the format being written, such as `Undefined` in MessagePack
+    /// or a CBOR integer below `i64::MIN` in an `Unpacked`
+    NoEquivalent,
+    /// A CBOR tag, of the number given, was found in strict mode
+    Tag(u64),
+    /// A CBOR string, array or map of indefinite length was found
+    IndefiniteLength,
+    /// Reading from or writing to the underlying stream failed
+    Io(io::Error),
+    /// Text could not be parsed as JSON
+    #[cfg(feature = "json")]
+    Json(serde_json::Error),
+    /// A custom error raised by a serde `Serialize` or `Deserialize` implementation
+    Message(String),
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::EndOfData {
+                expected,
+                available,
+            } => write!(
+                f,
+                "unexpected end of data: needed {} bytes but only {} were available",
+                expected, available
+            ),
+            ErrorKind::StringParseError => write!(f, "string is not valid UTF-8"),
+            ErrorKind::DepthLimitExceeded(limit) => {
+                write!(f, "values are nested more than {} deep", limit)
+            }
+            ErrorKind::ElementLimitExceeded(limit) => {
+                write!(f, "value has more than {} elements", limit)
+            }
+            ErrorKind::StringLengthLimitExceeded(limit) => {
+                write!(f, "string or raw is longer than {} bytes", limit)
+            }
+            ErrorKind::ByteLimitExceeded(limit) => {
+                write!(f, "value takes up more than {} bytes", limit)
+            }
+            ErrorKind::MapLengthLimitExceeded(limit) => {
+                write!(f, "map has more than {} entries", limit)
+            }
+            ErrorKind::ReservedType => write!(f, "reserved type byte"),
+            ErrorKind::TrailingData => write!(f, "unexpected bytes after the value"),
+            ErrorKind::NonMinimalEncoding => write!(f, "value is not minimally encoded"),
+            ErrorKind::NonStringKey => write!(f, "map key is not a string"),
+            ErrorKind::TooLarge(size) => write!(
+                f,
+                "length {} is too large to pack, the limit is {}",
+                size,
+                u32::MAX
+            ),
+            ErrorKind::UnsafeInteger(n) => write!(
+                f,
+                "integer {} cannot be represented exactly by a JavaScript number",
+                n
+            ),
+            ErrorKind::Extension(type_) => {
+                write!(f, "MessagePack extension type {} is not supported", type_)
+            }
+            ErrorKind::NoEquivalent => write!(f, "value has no equivalent in the output format"),
+            ErrorKind::Tag(tag) => write!(f, "CBOR tag {} is not supported", tag),
+            ErrorKind::IndefiniteLength => {
+                write!(f, "CBOR items of indefinite length are not supported")
+            }
+            ErrorKind::Io(e) => write!(f, "{}", e),
+            #[cfg(feature = "json")]
+            ErrorKind::Json(e) => write!(f, "{}", e),
+            ErrorKind::Message(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+/// One step of the path from the outermost value to the one that failed
+#[derive(Clone, Debug, PartialEq)]
+pub enum PathSegment {
+    /// An element of an array
+    Index(usize),
+    /// The value stored under a key of a map
+    Key(Unpacked),
+    /// The key of the n-th entry of a map, which could not be decoded itself
+    MapKey(usize),
+}
+
+impl fmt::Display for PathSegment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PathSegment::Index(i) => write!(f, "[{}]", i),
+            PathSegment::Key(Unpacked::String(s)) => write!(f, "[{:?}]", s),
+            PathSegment::Key(key) => write!(f, "[{:?}]", key),
+            PathSegment::MapKey(i) => write!(f, ".<key {}>", i),
+        }
+    }
+}
+
+/// An error raised while packing or unpacking
+///
+/// Besides its `kind`, an error raised while unpacking records where it happened: the byte
+/// offset and type byte of the value that could not be decoded, and the path of array indices
+/// and map keys leading to it from the outermost value.
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    offset: Option<usize>,
+    type_byte: Option<u8>,
+    path: Vec<PathSegment>,
+}
+
+impl Error {
+    /// An error of the kind given, with no offset, type byte or path recorded
+    pub fn new(kind: ErrorKind) -> Error {
+        Error {
+            kind,
+            offset: None,
+            type_byte: None,
+            path: vec![],
+        }
+    }
+
+    pub(crate) fn end_of_data(expected: usize, available: usize) -> Error {
+        Error::new(ErrorKind::EndOfData {
+            expected,
+            available,
+        })
+    }
+
+    /// The kind of failure that occurred
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    /// Returns true if the input ended before a value was complete
+    pub fn is_end_of_data(&self) -> bool {
+        matches!(self.kind, ErrorKind::EndOfData { .. })
+    }
+
+    /// The offset into the input of the value that could not be decoded
+    pub fn offset(&self) -> Option<usize> {
+        self.offset
+    }
+
+    /// The type byte of the value that could not be decoded
+    pub fn type_byte(&self) -> Option<u8> {
+        self.type_byte
+    }
+
+    /// The path from the outermost value to the one that could not be decoded
+    pub fn path(&self) -> &[PathSegment] {
+        &self.path
+    }
+
+    /// Records the value being decoded when the error occurred, unless a more deeply nested one
+    /// was already recorded
+    pub(crate) fn at(mut self, offset: usize, type_byte: Option<u8>) -> Error {
+        if self.offset.is_none() {
+            self.offset = Some(offset);
+            self.type_byte = type_byte;
+        }
+        self
+    }
+
+    /// Moves the recorded offset by `by` bytes, for input that was decoded from part way through
+    pub(crate) fn shifted(mut self, by: usize) -> Error {
+        self.offset = self.offset.map(|offset| offset + by);
+        self
+    }
+
+    /// Adds steps, outermost first, to the front of the path, as the error propagates out of the
+    /// containers it happened in
+    pub(crate) fn within<I: IntoIterator<Item = PathSegment>>(mut self, segments: I) -> Error {
+        self.path.splice(0..0, segments);
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(offset) = self.offset {
+            write!(f, " at byte {}", offset)?;
+        }
+        if let Some(type_byte) = self.type_byte {
+            write!(f, " (type 0x{:02x})", type_byte)?;
+        }
+        if !self.path.is_empty() {
+            write!(f, " in value")?;
+            for segment in self.path.iter() {
+                write!(f, "{}", segment)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match &self.kind {
+            ErrorKind::Io(e) => Some(e),
+            #[cfg(feature = "json")]
+            ErrorKind::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Error::new(kind)
+    }
 }
 
 impl From<FromUtf8Error> for Error {
     fn from(_error: FromUtf8Error) -> Self {
-        Error::StringParseError
+        Error::new(ErrorKind::StringParseError)
+    }
+}
+
+impl From<Utf8Error> for Error {
+    fn from(_error: Utf8Error) -> Self {
+        Error::new(ErrorKind::StringParseError)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::new(ErrorKind::Io(error))
+    }
+}
+
+#[cfg(feature = "json")]
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::new(ErrorKind::Json(error))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::new(ErrorKind::Message(msg.to_string()))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::new(ErrorKind::Message(msg.to_string()))
+    }
+}
+
+/// The kind of problem behind a `Warning`
+#[derive(Clone, Debug, PartialEq)]
+pub enum WarningKind {
+    /// A string was not valid UTF-8, and its invalid sequences were replaced
+    ReplacedInvalidUtf8,
+    /// A string was not valid UTF-8, and was returned as `Raw`
+    InvalidUtf8AsRaw,
+    /// An integer beyond ±(2^53 - 1) was read as a number with `UnpackOptions::js_numbers`, and
+    /// converted to the nearest `Double`
+    UnsafeInteger {
+        /// Whether the `Double` differs from the integer, which it does unless the integer
+        /// happens to be representable
+        rounded: bool,
+    },
+    /// A MessagePack extension value, of the type given, was read as `Undefined`
+    SkippedExtension(i8),
+    /// A CBOR tag, of the number given, was ignored and the value it tagged read on its own
+    SkippedTag(u64),
+}
+
+impl fmt::Display for WarningKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WarningKind::ReplacedInvalidUtf8 => {
+                write!(f, "invalid UTF-8 in string was replaced")
+            }
+            WarningKind::InvalidUtf8AsRaw => {
+                write!(f, "string with invalid UTF-8 was returned as raw bytes")
+            }
+            WarningKind::UnsafeInteger { rounded: true } => {
+                write!(f, "integer too large for a JavaScript number was rounded")
+            }
+            WarningKind::UnsafeInteger { rounded: false } => {
+                write!(f, "integer is outside the JavaScript safe integer range")
+            }
+            WarningKind::SkippedExtension(type_) => {
+                write!(
+                    f,
+                    "MessagePack extension type {} was read as undefined",
+                    type_
+                )
+            }
+            WarningKind::SkippedTag(tag) => write!(f, "CBOR tag {} was ignored", tag),
+        }
+    }
+}
+
+/// A problem in the input that was repaired while unpacking instead of failing
+#[derive(Clone, Debug, PartialEq)]
+pub struct Warning {
+    kind: WarningKind,
+    offset: usize,
+}
+
+impl Warning {
+    pub(crate) fn new(kind: WarningKind, offset: usize) -> Warning {
+        Warning { kind, offset }
+    }
+
+    pub fn kind(&self) -> &WarningKind {
+        &self.kind
+    }
+
+    /// The offset into the input of the value that was repaired
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Moves the offset by `by` bytes, for input that was decoded from part way through
+    pub(crate) fn shifted(mut self, by: usize) -> Warning {
+        self.offset += by;
+        self
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at byte {}", self.kind, self.offset)
     }
 }
 
 pub type Result<T> = result::Result<T, Error>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::binarypack::unpack;
+
+    #[test]
+    fn context() {
+        let packed = binarypack!({ "users": [1, 2, 3, "abc"] }).pack();
+        let e = unpack(&packed[..packed.len() - 1]).unwrap_err();
+        assert!(matches!(
+            e.kind(),
+            ErrorKind::EndOfData {
+                expected: 3,
+                available: 2
+            }
+        ));
+        assert_eq!(e.offset(), Some(packed.len() - 6));
+        assert_eq!(e.type_byte(), Some(0xd8));
+        assert_eq!(
+            e.path(),
+            &[
+                PathSegment::Key(Unpacked::String("users".to_string())),
+                PathSegment::Index(3)
+            ]
+        );
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "unexpected end of data: needed 3 bytes but only 2 were available at byte {} \
+                 (type 0xd8) in value[\"users\"][3]",
+                packed.len() - 6
+            )
+        );
+
+        let e = unpack(&[0x91, 0x81, 0xd8, 0, 2, 0xff, 0xfe]).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::StringParseError));
+        assert_eq!(e.offset(), Some(2));
+        assert_eq!(e.path(), &[PathSegment::Index(0), PathSegment::MapKey(0)]);
+    }
+
+    #[test]
+    fn boxed() {
+        fn parse(data: &[u8]) -> result::Result<Unpacked, Box<dyn error::Error + Send + Sync>> {
+            Ok(unpack(data)?)
+        }
+
+        let e = parse(&[0xcd]).unwrap_err();
+        assert!(e.downcast_ref::<Error>().unwrap().is_end_of_data());
+    }
+}
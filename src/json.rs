@@ -0,0 +1,418 @@
+//! Convert `Unpacked` values to and from JSON text
+//!
+//! JSON has no equivalent of `Raw`, `Undefined`, map keys other than strings, or NaN and the
+//! infinities, so `JsonOptions` chooses how each of them is written. Integers of every width
+//! become JSON numbers, and `Float`s are written as the `f64` of the same value.
+//!
+//! Reading JSON, `null`, booleans, strings, arrays and objects become `Null`, `Bool`, `String`,
+//! `Array` and `Map`. An integer becomes the variant js-binarypack would pack it as, and so the
+//! variant a JavaScript peer's value unpacks as: `Uint8` for 3 and `Int16` for -300. Any other
+//! number becomes a `Double`. Nothing in JSON marks a string as bytes, so `Raw` values written
+//! as base64 or hex read back as `String`s.
+
+use std::convert::TryFrom;
+use std::iter;
+
+use serde_json::{Map, Number, Value};
+
+use crate::binarypack::Unpacked;
+use crate::error::{Error, ErrorKind, PathSegment, Result};
+
+/// How `Raw` values are written
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RawJson {
+    /// As a string of standard, padded base64
+    Base64,
+    /// As a string of lowercase hex digits
+    Hex,
+    /// As an array of the byte values
+    Array,
+}
+
+/// How `Undefined` is written
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UndefinedJson {
+    /// As `null`
+    Null,
+    /// Fail with `ErrorKind::NoEquivalent`
+    Error,
+}
+
+/// How map keys other than strings are written
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyJson {
+    /// As the JSON text of the key, e.g. `"1"` for an integer, unless the key is written as a
+    /// string itself like a `Raw` in base64. If two keys of a map become the same string, such
+    /// as `Uint8(1)` and `"1"`, the conversion fails with `ErrorKind::NoEquivalent`.
+    Stringify,
+    /// Fail with `ErrorKind::NoEquivalent`
+    Error,
+}
+
+/// How NaN and infinite floats are written
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NonFiniteJson {
+    /// As `null`, as JavaScript's `JSON.stringify` does
+    Null,
+    /// As the strings `"NaN"`, `"Infinity"` and `"-Infinity"`
+    String,
+    /// Fail with `ErrorKind::NoEquivalent`
+    Error,
+}
+
+/// Choices that apply while converting to JSON
+#[derive(Clone, Debug, PartialEq)]
+pub struct JsonOptions {
+    /// How `Raw` values are written
+    pub raw: RawJson,
+    /// How `Undefined` is written
+    pub undefined: UndefinedJson,
+    /// How map keys other than strings are written
+    pub keys: KeyJson,
+    /// How NaN and infinite `Float`s and `Double`s are written
+    pub non_finite: NonFiniteJson,
+    /// Writes the text indented over several lines
+    pub pretty: bool,
+}
+
+impl Default for JsonOptions {
+    /// Writes raw bytes as base64, `Undefined` and non-finite floats as `null`, and stringifies
+    /// keys, so that every value can be converted
+    fn default() -> Self {
+        JsonOptions {
+            raw: RawJson::Base64,
+            undefined: UndefinedJson::Null,
+            keys: KeyJson::Stringify,
+            non_finite: NonFiniteJson::Null,
+            pretty: false,
+        }
+    }
+}
+
+/// Converts a value to JSON text
+pub fn to_json(value: &Unpacked) -> Result<String> {
+    to_json_with_options(value, &JsonOptions::default())
+}
+
+/// Converts a value to JSON text using `options`
+///
+/// Errors record the path to the value that could not be converted.
+pub fn to_json_with_options(value: &Unpacked, options: &JsonOptions) -> Result<String> {
+    let json = to_json_value(value, options)?;
+    if options.pretty {
+        Ok(serde_json::to_string_pretty(&json)?)
+    } else {
+        Ok(json.to_string())
+    }
+}
+
+/// Converts a value to a `serde_json::Value` using `options`
+pub fn to_json_value(value: &Unpacked, options: &JsonOptions) -> Result<Value> {
+    Ok(match value {
+        Unpacked::Uint8(a) => (*a).into(),
+        Unpacked::Uint16(a) => (*a).into(),
+        Unpacked::Uint32(a) => (*a).into(),
+        Unpacked::Uint64(a) => (*a).into(),
+        Unpacked::Int8(a) => (*a).into(),
+        Unpacked::Int16(a) => (*a).into(),
+        Unpacked::Int32(a) => (*a).into(),
+        Unpacked::Int64(a) => (*a).into(),
+        Unpacked::Float(f) => float((*f).into(), options)?,
+        Unpacked::Double(f) => float(*f, options)?,
+        Unpacked::Bool(b) => Value::Bool(*b),
+        Unpacked::Raw(bytes) => match options.raw {
+            RawJson::Base64 => Value::String(base64(bytes)),
+            RawJson::Hex => Value::String(hex(bytes)),
+            RawJson::Array => Value::Array(bytes.iter().map(|&b| b.into()).collect()),
+        },
+        Unpacked::String(s) => Value::String(s.clone()),
+        Unpacked::Null => Value::Null,
+        Unpacked::Undefined => match options.undefined {
+            UndefinedJson::Null => Value::Null,
+            UndefinedJson::Error => return Err(ErrorKind::NoEquivalent.into()),
+        },
+        Unpacked::Array(v) => Value::Array(
+            v.iter()
+                .enumerate()
+                .map(|(i, element)| {
+                    to_json_value(element, options)
+                        .map_err(|e| e.within(iter::once(PathSegment::Index(i))))
+                })
+                .collect::<Result<_>>()?,
+        ),
+        Unpacked::Map(m) => {
+            let mut object = Map::new();
+            for (i, (key, value)) in m.iter().enumerate() {
+                let k = json_key(key, options)
+                    .map_err(|e| e.within(iter::once(PathSegment::MapKey(i))))?;
+                if object.contains_key(&k) {
+                    return Err(Error::new(ErrorKind::NoEquivalent)
+                        .within(iter::once(PathSegment::MapKey(i))));
+                }
+                let v = to_json_value(value, options)
+                    .map_err(|e| e.within(iter::once(PathSegment::Key(key.clone()))))?;
+                object.insert(k, v);
+            }
+            Value::Object(object)
+        }
+    })
+}
+
+fn json_key(key: &Unpacked, options: &JsonOptions) -> Result<String> {
+    if let Unpacked::String(s) = key {
+        return Ok(s.clone());
+    }
+    if options.keys == KeyJson::Error {
+        return Err(ErrorKind::NoEquivalent.into());
+    }
+
+    Ok(match to_json_value(key, options)? {
+        Value::String(s) => s,
+        json => json.to_string(),
+    })
+}
+
+fn float(f: f64, options: &JsonOptions) -> Result<Value> {
+    if let Some(n) = Number::from_f64(f) {
+        return Ok(Value::Number(n));
+    }
+
+    let name = if f.is_nan() {
+        "NaN"
+    } else if f > 0.0 {
+        "Infinity"
+    } else {
+        "-Infinity"
+    };
+    match options.non_finite {
+        NonFiniteJson::Null => Ok(Value::Null),
+        NonFiniteJson::String => Ok(Value::String(name.to_string())),
+        NonFiniteJson::Error => Err(ErrorKind::NoEquivalent.into()),
+    }
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut s = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                s.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                s.push('=');
+            }
+        }
+    }
+    s
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parses JSON text into a value
+pub fn from_json(text: &str) -> Result<Unpacked> {
+    let json: Value = serde_json::from_str(text)?;
+    Ok(from_json_value(&json))
+}
+
+/// Converts a `serde_json::Value` into a value
+pub fn from_json_value(json: &Value) -> Unpacked {
+    match json {
+        Value::Null => Unpacked::Null,
+        Value::Bool(b) => Unpacked::Bool(*b),
+        Value::Number(n) => {
+            if let Some(a) = n.as_i64() {
+                integer(a.into())
+            } else if let Some(a) = n.as_u64() {
+                integer(a.into())
+            } else {
+                Unpacked::Double(n.as_f64().unwrap_or(f64::NAN))
+            }
+        }
+        Value::String(s) => Unpacked::String(s.clone()),
+        Value::Array(v) => Unpacked::Array(v.iter().map(from_json_value).collect()),
+        Value::Object(m) => Unpacked::Map(
+            m.iter()
+                .map(|(k, v)| (Unpacked::String(k.clone()), from_json_value(v)))
+                .collect(),
+        ),
+    }
+}
+
+/// The variant that js-binarypack packs an integer as, tried in the same order
+fn integer(n: i128) -> Unpacked {
+    if let Ok(a) = u8::try_from(n) {
+        Unpacked::Uint8(a)
+    } else if let Ok(a) = i8::try_from(n) {
+        Unpacked::Int8(a)
+    } else if let Ok(a) = u16::try_from(n) {
+        Unpacked::Uint16(a)
+    } else if let Ok(a) = i16::try_from(n) {
+        Unpacked::Int16(a)
+    } else if let Ok(a) = u32::try_from(n) {
+        Unpacked::Uint32(a)
+    } else if let Ok(a) = i32::try_from(n) {
+        Unpacked::Int32(a)
+    } else if let Ok(a) = i64::try_from(n) {
+        Unpacked::Int64(a)
+    } else {
+        Unpacked::Uint64(n as u64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::binarypack::{unpack, PackOptions};
+
+    #[test]
+    fn to_text() {
+        let value = binarypack!({
+            "a": [1u8, -2i64, 2.5, Unpacked::Float(0.5), "x", null, true],
+            "raw": b"\x00\xff\x10",
+            1: undefined,
+            [1, 2]: f64::NAN
+        });
+        assert_eq!(
+            to_json(&value).unwrap(),
+            r#"{"1":null,"[1,2]":null,"a":[1,-2,2.5,0.5,"x",null,true],"raw":"AP8Q"}"#
+        );
+
+        let options = JsonOptions {
+            raw: RawJson::Hex,
+            non_finite: NonFiniteJson::String,
+            ..Default::default()
+        };
+        let json = to_json_value(&value, &options).unwrap();
+        assert_eq!(json["raw"], "00ff10");
+        assert_eq!(json["[1,2]"], "NaN");
+
+        let options = JsonOptions {
+            raw: RawJson::Array,
+            ..Default::default()
+        };
+        let json = to_json_value(&binarypack!({ b"k": b"\x01\x02" }), &options).unwrap();
+        assert_eq!(json.to_string(), r#"{"[107]":[1,2]}"#);
+
+        let value = binarypack!([f64::NEG_INFINITY, Unpacked::Float(f32::INFINITY)]);
+        let json = to_json_value(
+            &value,
+            &JsonOptions {
+                non_finite: NonFiniteJson::String,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(json.to_string(), r#"["-Infinity","Infinity"]"#);
+
+        assert_eq!(
+            to_json_with_options(
+                &binarypack!([1]),
+                &JsonOptions {
+                    pretty: true,
+                    ..Default::default()
+                }
+            )
+            .unwrap(),
+            "[\n  1\n]"
+        );
+    }
+
+    #[test]
+    fn base64_lengths() {
+        let cases = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (bytes, expected) in cases.iter() {
+            assert_eq!(base64(bytes.as_bytes()), *expected);
+        }
+    }
+
+    #[test]
+    fn no_equivalent() {
+        let options = JsonOptions {
+            undefined: UndefinedJson::Error,
+            keys: KeyJson::Error,
+            non_finite: NonFiniteJson::Error,
+            ..Default::default()
+        };
+
+        let e = to_json_with_options(&binarypack!({ "a": [1, undefined] }), &options).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::NoEquivalent));
+        assert_eq!(
+            e.path(),
+            &[
+                PathSegment::Key(Unpacked::String("a".to_string())),
+                PathSegment::Index(1)
+            ]
+        );
+
+        let e = to_json_with_options(&binarypack!({ 1: 2 }), &options).unwrap_err();
+        assert_eq!(e.path(), &[PathSegment::MapKey(0)]);
+
+        let e = to_json_with_options(&binarypack!([f64::INFINITY]), &options).unwrap_err();
+        assert_eq!(e.path(), &[PathSegment::Index(0)]);
+    }
+
+    #[test]
+    fn colliding_keys() {
+        let values = [
+            binarypack!({ 1u8: "a", "1": "b" }),
+            binarypack!({ 1u8: "a", 1i16: "b" }),
+            binarypack!({ "x": { [1]: 0, "[1]": 1 } }),
+        ];
+        for value in values.iter() {
+            let e = to_json(value).unwrap_err();
+            assert!(matches!(e.kind(), ErrorKind::NoEquivalent), "{:?}", value);
+            assert!(
+                matches!(e.path().last(), Some(PathSegment::MapKey(_))),
+                "{:?}",
+                value
+            );
+        }
+
+        assert_eq!(
+            to_json(&binarypack!({ 1u8: "a", "2": "b" })).unwrap(),
+            r#"{"1":"a","2":"b"}"#
+        );
+    }
+
+    #[test]
+    fn from_text() {
+        let value = from_json(
+            r#"{"n": [3, -300, 70000, -1, 1.5, 18446744073709551615], "s": "x", "z": null}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            value,
+            binarypack!({
+                "n": [3u8, -300i16, 70000u32, -1i8, 1.5, u64::MAX],
+                "s": "x",
+                "z": null
+            })
+        );
+
+        let narrow = PackOptions {
+            compact: true,
+            narrow_integers: true,
+            ..Default::default()
+        };
+        let packed = value.try_pack_with(&narrow).unwrap();
+        assert_eq!(unpack(&packed).unwrap(), value);
+
+        let e = from_json("[1,").unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::Json(_)));
+    }
+}
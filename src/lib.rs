@@ -1,10 +1,27 @@
+#[macro_use]
+mod macros;
+
 pub mod binarypack;
+pub mod cbor;
+#[cfg(feature = "serde")]
+pub mod de;
+pub mod decoder;
 pub mod error;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod msgpack;
+pub mod reader;
+#[cfg(feature = "serde")]
+pub mod ser;
+pub mod transcode;
+pub mod validate;
+pub mod writer;
 
 #[cfg(test)]
 mod tests {
     use crate::binarypack;
     #[test]
+    #[allow(clippy::single_match)]
     fn binarypack_unpack() {
         let a = [1, 2, 3];
         match binarypack::unpack(&a).unwrap() {
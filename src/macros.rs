@@ -0,0 +1,190 @@
+/// Constructs an `Unpacked` value from a literal
+///
+/// Arrays are written as `[...]` and maps as `{ key: value, ... }`, where keys and values may
+/// themselves be any `binarypack!` literal. `null` and `undefined` produce `Null` and
+/// `Undefined`, and any other expression is converted with `Unpacked::from`, so the variant
+/// follows the expression's type: `"text"` is a `String`, `b"bytes"` is `Raw`, `2.5` is a
+/// `Double`, and an integer is packed with the width of its type, e.g. `1` is an `Int32` and
+/// `1u8` a `Uint8`.
+///
+/// The outer braces of a map may be left off:
+///
+/// ```
+/// use peerrs_binarypack::binarypack;
+///
+/// let value = binarypack!{ "key": [1, 2.5, null, true], "raw": b"\x00\x01" };
+/// ```
+#[macro_export]
+macro_rules! binarypack {
+    // Munches the elements of an array, taking a whole element at each step. `null`,
+    // `undefined`, arrays and maps are matched before `expr` so that they are not parsed as
+    // Rust expressions.
+    (@array [$($elems:expr,)*]) => {
+        ::std::vec![$($elems,)*]
+    };
+    (@array [$($elems:expr,)*] null $($rest:tt)*) => {
+        $crate::binarypack!(@array [$($elems,)* $crate::binarypack!(null),] $($rest)*)
+    };
+    (@array [$($elems:expr,)*] undefined $($rest:tt)*) => {
+        $crate::binarypack!(@array [$($elems,)* $crate::binarypack!(undefined),] $($rest)*)
+    };
+    (@array [$($elems:expr,)*] [$($array:tt)*] $($rest:tt)*) => {
+        $crate::binarypack!(@array [$($elems,)* $crate::binarypack!([$($array)*]),] $($rest)*)
+    };
+    (@array [$($elems:expr,)*] {$($map:tt)*} $($rest:tt)*) => {
+        $crate::binarypack!(@array [$($elems,)* $crate::binarypack!({$($map)*}),] $($rest)*)
+    };
+    (@array [$($elems:expr,)*] $next:expr , $($rest:tt)*) => {
+        $crate::binarypack!(@array [$($elems,)* $crate::binarypack!($next),] $($rest)*)
+    };
+    (@array [$($elems:expr,)*] $last:expr) => {
+        $crate::binarypack!(@array [$($elems,)* $crate::binarypack!($last),])
+    };
+    (@array [$($elems:expr,)*] , $($rest:tt)*) => {
+        $crate::binarypack!(@array [$($elems,)*] $($rest)*)
+    };
+
+    // Munches the entries of a map, collecting the tokens of a key until a colon and then
+    // taking its whole value
+    (@map $map:ident []) => {};
+    (@map $map:ident [$($key:tt)+] : null $(, $($rest:tt)*)?) => {
+        $crate::binarypack!(@insert $map [$($key)+] null);
+        $crate::binarypack!(@map $map [] $($($rest)*)?);
+    };
+    (@map $map:ident [$($key:tt)+] : undefined $(, $($rest:tt)*)?) => {
+        $crate::binarypack!(@insert $map [$($key)+] undefined);
+        $crate::binarypack!(@map $map [] $($($rest)*)?);
+    };
+    (@map $map:ident [$($key:tt)+] : [$($array:tt)*] $(, $($rest:tt)*)?) => {
+        $crate::binarypack!(@insert $map [$($key)+] [$($array)*]);
+        $crate::binarypack!(@map $map [] $($($rest)*)?);
+    };
+    (@map $map:ident [$($key:tt)+] : {$($inner:tt)*} $(, $($rest:tt)*)?) => {
+        $crate::binarypack!(@insert $map [$($key)+] {$($inner)*});
+        $crate::binarypack!(@map $map [] $($($rest)*)?);
+    };
+    (@map $map:ident [$($key:tt)+] : $value:expr $(, $($rest:tt)*)?) => {
+        $crate::binarypack!(@insert $map [$($key)+] $value);
+        $crate::binarypack!(@map $map [] $($($rest)*)?);
+    };
+    (@map $map:ident [$($key:tt)*] $next:tt $($rest:tt)*) => {
+        $crate::binarypack!(@map $map [$($key)* $next] $($rest)*)
+    };
+    (@insert $map:ident [$($key:tt)+] $($value:tt)+) => {
+        $map.insert($crate::binarypack!($($key)+), $crate::binarypack!($($value)+))
+    };
+
+    (null) => {
+        $crate::binarypack::Unpacked::Null
+    };
+    (undefined) => {
+        $crate::binarypack::Unpacked::Undefined
+    };
+    ([$($tt:tt)*]) => {
+        $crate::binarypack::Unpacked::Array($crate::binarypack!(@array [] $($tt)*))
+    };
+    ({$($tt:tt)*}) => {{
+        #[allow(unused_mut)]
+        let mut map = ::std::collections::HashMap::new();
+        $crate::binarypack!(@map map [] $($tt)*);
+        $crate::binarypack::Unpacked::Map(map)
+    }};
+    ($key:tt : $($rest:tt)*) => {
+        $crate::binarypack!({$key : $($rest)*})
+    };
+    ($other:expr) => {
+        $crate::binarypack::Unpacked::from($other)
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use crate::binarypack::Unpacked;
+    use std::collections::HashMap;
+
+    #[test]
+    fn scalars() {
+        assert_eq!(binarypack!(null), Unpacked::Null);
+        assert!(matches!(binarypack!(undefined), Unpacked::Undefined));
+        assert_eq!(binarypack!(true), Unpacked::Bool(true));
+        assert_eq!(binarypack!(1), Unpacked::Int32(1));
+        assert_eq!(binarypack!(-1), Unpacked::Int32(-1));
+        assert_eq!(binarypack!(200u8), Unpacked::Uint8(200));
+        assert_eq!(binarypack!(2.5), Unpacked::Double(2.5));
+        assert_eq!(binarypack!("x"), Unpacked::String("x".to_string()));
+        assert_eq!(binarypack!(b"\x01\x02"), Unpacked::Raw(vec![1, 2]));
+
+        let n = 3u16;
+        assert_eq!(binarypack!(n + 1), Unpacked::Uint16(4));
+        assert_eq!(binarypack!(None::<u8>), Unpacked::Null);
+    }
+
+    #[test]
+    fn containers() {
+        assert_eq!(binarypack!([]), Unpacked::Array(vec![]));
+        assert_eq!(binarypack!({}), Unpacked::Map(HashMap::new()));
+        assert_eq!(
+            binarypack!([1u8, -2, [null],]),
+            Unpacked::Array(vec![
+                Unpacked::Uint8(1),
+                Unpacked::Int32(-2),
+                Unpacked::Array(vec![Unpacked::Null])
+            ])
+        );
+
+        let value = binarypack! {
+            "key": [1, 2.5, null, true],
+            "raw": b"ab",
+            -1: { "nested": "map" },
+        };
+
+        let mut nested = HashMap::new();
+        nested.insert(
+            Unpacked::String("nested".to_string()),
+            Unpacked::String("map".to_string()),
+        );
+        let mut m = HashMap::new();
+        m.insert(
+            Unpacked::String("key".to_string()),
+            Unpacked::Array(vec![
+                Unpacked::Int32(1),
+                Unpacked::Double(2.5),
+                Unpacked::Null,
+                Unpacked::Bool(true),
+            ]),
+        );
+        m.insert(
+            Unpacked::String("raw".to_string()),
+            Unpacked::Raw(vec![97, 98]),
+        );
+        m.insert(Unpacked::Int32(-1), Unpacked::Map(nested));
+        assert_eq!(value, Unpacked::Map(m));
+    }
+
+    #[test]
+    fn long_literals() {
+        let value = binarypack!([
+            0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23,
+            24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45,
+            46, 47, 48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63, 64, 65, 66, 67,
+            68, 69, 70, 71, 72, 73, 74, 75, 76, 77, 78, 79, 80, 81, 82, 83, 84, 85, 86, 87, 88, 89,
+            90, 91, 92, 93, 94, 95, 96, 97, 98, 99
+        ]);
+        assert_eq!(
+            value,
+            Unpacked::Array((0..100).map(Unpacked::Int32).collect())
+        );
+
+        let value = binarypack!({
+            0: 0, 1: 1, 2: 2, 3: 3, 4: 4, 5: 5, 6: 6, 7: 7, 8: 8, 9: 9, 10: 10, 11: 11, 12: 12,
+            13: 13, 14: 14, 15: 15, 16: 16, 17: 17, 18: 18, 19: 19, 20: 20, 21: 21, 22: 22, 23: 23,
+            24: 24, 25: 25, 26: 26, 27: 27, 28: 28, 29: 29, 30: 30, 31: 31, 32: 32, 33: 33, 34: 34,
+            35: 35, 36: 36, 37: 37, 38: 38, 39: 39, 40: 40, 41: 41, 42: 42, 43: 43, 44: 44, 45: 45,
+            46: 46, 47: 47, 48: 48, 49: 49
+        });
+        let m = (0..50)
+            .map(|i| (Unpacked::Int32(i), Unpacked::Int32(i)))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(value, Unpacked::Map(m));
+    }
+}
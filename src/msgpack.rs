@@ -0,0 +1,434 @@
+//! Read standard MessagePack into `Unpacked` values, and write them as MessagePack
+//!
+//! js-binarypack is derived from MessagePack but assigns several type bytes differently, so
+//! `binarypack::unpack` misreads MessagePack that uses them:
+//!
+//! | Byte          | js-binarypack          | MessagePack            |
+//! |---------------|------------------------|------------------------|
+//! | `0xa0..=0xaf` | raw of 0 to 15 bytes   | str of 0 to 15 bytes   |
+//! | `0xb0..=0xbf` | str of 0 to 15 bytes   | str of 16 to 31 bytes  |
+//! | `0xc1`        | undefined              | never used             |
+//! | `0xc4..=0xc6` | reserved               | bin 8, bin 16, bin 32  |
+//! | `0xc7..=0xc9` | reserved               | ext 8, ext 16, ext 32  |
+//! | `0xd4..=0xd7` | reserved               | fixext 1, 2, 4 and 8   |
+//! | `0xd8`        | str 16                 | fixext 16              |
+//! | `0xd9`        | str 32                 | str 8                  |
+//! | `0xda`        | raw 16                 | str 16                 |
+//! | `0xdb`        | raw 32                 | str 32                 |
+//!
+//! Every other byte means the same in both formats. MessagePack bin values unpack as `Raw`.
+//! `Unpacked` has no equivalent of extension values, so they are read as `Undefined` and
+//! recorded as a `Warning`. The never used byte `0xc1` is also read as `Undefined`.
+//!
+//! `UnpackOptions` apply as they do to js-binarypack. In strict mode extension values fail
+//! with `ErrorKind::Extension` and `0xc1` with `ErrorKind::ReservedType`, and every length
+//! must be written in the narrowest form that holds it.
+//!
+//! `pack` and `pack_to` write values using the MessagePack meanings of these bytes. `Undefined`
+//! has no MessagePack equivalent, and is written as nil or fails with `ErrorKind::NoEquivalent`.
+//! The `transcode` module converts values between the two formats without building them.
+
+use std::io::Write;
+
+use crate::binarypack::{Format, Token, UnpackOptions, Unpacked, UnpackedRef, Unpacker};
+use crate::error::{ErrorKind, Result, WarningKind};
+
+pub(crate) const NIL: u8 = 0xc0;
+pub(crate) const NEVER_USED: u8 = 0xc1;
+pub(crate) const FALSE: u8 = 0xc2;
+pub(crate) const TRUE: u8 = 0xc3;
+pub(crate) const BIN8: u8 = 0xc4;
+pub(crate) const BIN16: u8 = 0xc5;
+pub(crate) const BIN32: u8 = 0xc6;
+pub(crate) const EXT8: u8 = 0xc7;
+pub(crate) const EXT16: u8 = 0xc8;
+pub(crate) const EXT32: u8 = 0xc9;
+pub(crate) const FLOAT32: u8 = 0xca;
+pub(crate) const FLOAT64: u8 = 0xcb;
+pub(crate) const UINT8: u8 = 0xcc;
+pub(crate) const UINT16: u8 = 0xcd;
+pub(crate) const UINT32: u8 = 0xce;
+pub(crate) const UINT64: u8 = 0xcf;
+pub(crate) const INT8: u8 = 0xd0;
+pub(crate) const INT16: u8 = 0xd1;
+pub(crate) const INT32: u8 = 0xd2;
+pub(crate) const INT64: u8 = 0xd3;
+pub(crate) const FIXEXT1: u8 = 0xd4;
+pub(crate) const FIXEXT2: u8 = 0xd5;
+pub(crate) const FIXEXT4: u8 = 0xd6;
+pub(crate) const FIXEXT8: u8 = 0xd7;
+pub(crate) const FIXEXT16: u8 = 0xd8;
+pub(crate) const STR8: u8 = 0xd9;
+pub(crate) const STR16: u8 = 0xda;
+pub(crate) const STR32: u8 = 0xdb;
+pub(crate) const ARRAY16: u8 = 0xdc;
+pub(crate) const ARRAY32: u8 = 0xdd;
+pub(crate) const MAP16: u8 = 0xde;
+pub(crate) const MAP32: u8 = 0xdf;
+
+impl<'a> Unpacker<'a> {
+    /// Unpacks a single MessagePack scalar value, or the header of an array or map
+    pub(crate) fn unpack_msgpack_token_body(&mut self, type_: u8) -> Result<Token<'a>> {
+        Ok(Token::Value(match type_ {
+            0x00..=0x7f => UnpackedRef::Uint8(type_),
+            0x80..=0x8f => return Ok(Token::Map((type_ & 0x0f) as usize)),
+            0x90..=0x9f => return Ok(Token::Array((type_ & 0x0f) as usize)),
+            0xa0..=0xbf => self.unpack_string((type_ & 0x1f) as usize)?,
+            0xe0..=0xff => UnpackedRef::Int8(type_ as i8),
+
+            NIL => UnpackedRef::Null,
+            NEVER_USED if self.options().strict => return Err(ErrorKind::ReservedType.into()),
+            NEVER_USED => UnpackedRef::Undefined,
+            FALSE => UnpackedRef::Bool(false),
+            TRUE => UnpackedRef::Bool(true),
+            BIN8 => {
+                let size = self.unpack_uint8()? as usize;
+                UnpackedRef::Raw(self.unpack_raw(size)?)
+            }
+            BIN16 => {
+                let size = self.unpack_msgpack_len_u16()?;
+                UnpackedRef::Raw(self.unpack_raw(size)?)
+            }
+            BIN32 => {
+                let size = self.unpack_len_u32()?;
+                UnpackedRef::Raw(self.unpack_raw(size)?)
+            }
+            EXT8 => {
+                let size = self.unpack_uint8()? as usize;
+                return self.unpack_ext(size);
+            }
+            EXT16 => {
+                let size = self.unpack_msgpack_len_u16()?;
+                return self.unpack_ext(size);
+            }
+            EXT32 => {
+                let size = self.unpack_len_u32()?;
+                return self.unpack_ext(size);
+            }
+            FLOAT32 => UnpackedRef::Float(self.unpack_float()?),
+            FLOAT64 => UnpackedRef::Double(self.unpack_double()?),
+            UINT8 => {
+                let a = self.unpack_uint8()?;
+                self.check_minimal(a >= 0x80)?;
+                UnpackedRef::Uint8(a)
+            }
+            UINT16 => UnpackedRef::Uint16(self.unpack_uint16()?),
+            UINT32 => UnpackedRef::Uint32(self.unpack_uint32()?),
+            UINT64 => UnpackedRef::Uint64(self.unpack_uint64()?),
+            INT8 => {
+                let a = self.unpack_int8()?;
+                self.check_minimal(!(-0x20..0).contains(&a))?;
+                UnpackedRef::Int8(a)
+            }
+            INT16 => UnpackedRef::Int16(self.unpack_int16()?),
+            INT32 => UnpackedRef::Int32(self.unpack_int32()?),
+            INT64 => UnpackedRef::Int64(self.unpack_int64()?),
+            FIXEXT1 => return self.unpack_ext(1),
+            FIXEXT2 => return self.unpack_ext(2),
+            FIXEXT4 => return self.unpack_ext(4),
+            FIXEXT8 => return self.unpack_ext(8),
+            FIXEXT16 => return self.unpack_ext(16),
+            STR8 => {
+                let size = self.unpack_uint8()? as usize;
+                self.check_minimal(size > 0x1f)?;
+                self.unpack_string(size)?
+            }
+            STR16 => {
+                let size = self.unpack_msgpack_len_u16()?;
+                self.unpack_string(size)?
+            }
+            STR32 => {
+                let size = self.unpack_len_u32()?;
+                self.unpack_string(size)?
+            }
+            ARRAY16 => {
+                let size = self.unpack_uint16()? as usize;
+                self.check_minimal(size > 0x0f)?;
+                return Ok(Token::Array(size));
+            }
+            ARRAY32 => return Ok(Token::Array(self.unpack_len_u32()?)),
+            MAP16 => {
+                let size = self.unpack_uint16()? as usize;
+                self.check_minimal(size > 0x0f)?;
+                return Ok(Token::Map(size));
+            }
+            MAP32 => return Ok(Token::Map(self.unpack_len_u32()?)),
+        }))
+    }
+
+    /// Unpacks a 16 bit length, which in strict mode must not fit in 8 bits
+    fn unpack_msgpack_len_u16(&mut self) -> Result<usize> {
+        let size = self.unpack_uint16()? as usize;
+        self.check_minimal(size > u8::MAX as usize)?;
+        Ok(size)
+    }
+
+    /// Skips the type and `size` bytes of data of an extension value
+    fn unpack_ext(&mut self, size: usize) -> Result<Token<'a>> {
+        let type_ = self.unpack_int8()?;
+        if self.options().strict {
+            return Err(ErrorKind::Extension(type_).into());
+        }
+
+        self.unpack_raw(size)?;
+        self.warn(WarningKind::SkippedExtension(type_));
+        Ok(Token::Extension(type_))
+    }
+}
+
+/// Writes a length in the narrowest of the forms given, the first of which holds lengths up to
+/// its maximum in the type byte itself
+fn pack_len<W: Write>(
+    packed: &mut W,
+    size: usize,
+    fix: Option<(u8, usize)>,
+    len8: Option<u8>,
+    len16: u8,
+    len32: u8,
+) -> Result<()> {
+    if size > u32::MAX as usize {
+        return Err(ErrorKind::TooLarge(size).into());
+    }
+
+    match (fix, len8) {
+        (Some((mask, max)), _) if size <= max => packed.write_all(&[mask | size as u8])?,
+        (_, Some(type_)) if size <= u8::MAX as usize => packed.write_all(&[type_, size as u8])?,
+        _ if size <= u16::MAX as usize => {
+            packed.write_all(&[len16])?;
+            packed.write_all(&(size as u16).to_be_bytes())?;
+        }
+        _ => {
+            packed.write_all(&[len32])?;
+            packed.write_all(&(size as u32).to_be_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes a single token as MessagePack, writing only the header of an array or map
+///
+/// Integers keep the width of their variant, except that those that fit in a fixint are
+/// written as one. `Undefined` is written as nil if `undefined_as_nil` is set, and otherwise
+/// fails with `ErrorKind::NoEquivalent`.
+pub(crate) fn pack_token<W: Write>(
+    packed: &mut W,
+    token: &Token,
+    undefined_as_nil: bool,
+) -> Result<()> {
+    match token {
+        Token::Array(size) => pack_len(packed, *size, Some((0x90, 0x0f)), None, ARRAY16, ARRAY32),
+        Token::Map(size) => pack_len(packed, *size, Some((0x80, 0x0f)), None, MAP16, MAP32),
+        Token::Value(value) => pack_value(packed, value, undefined_as_nil),
+        Token::Extension(type_) => Err(ErrorKind::Extension(*type_).into()),
+    }
+}
+
+fn pack_value<W: Write>(packed: &mut W, value: &UnpackedRef, undefined_as_nil: bool) -> Result<()> {
+    match value {
+        UnpackedRef::Uint8(a) if *a < 0x80 => packed.write_all(&[*a])?,
+        UnpackedRef::Uint8(a) => packed.write_all(&[UINT8, *a])?,
+        UnpackedRef::Uint16(a) => pack_number(packed, UINT16, &a.to_be_bytes())?,
+        UnpackedRef::Uint32(a) => pack_number(packed, UINT32, &a.to_be_bytes())?,
+        UnpackedRef::Uint64(a) => pack_number(packed, UINT64, &a.to_be_bytes())?,
+        UnpackedRef::Int8(a) if (-0x20..0).contains(a) => packed.write_all(&[*a as u8])?,
+        UnpackedRef::Int8(a) => packed.write_all(&[INT8, *a as u8])?,
+        UnpackedRef::Int16(a) => pack_number(packed, INT16, &a.to_be_bytes())?,
+        UnpackedRef::Int32(a) => pack_number(packed, INT32, &a.to_be_bytes())?,
+        UnpackedRef::Int64(a) => pack_number(packed, INT64, &a.to_be_bytes())?,
+        UnpackedRef::Float(f) => pack_number(packed, FLOAT32, &f.to_be_bytes())?,
+        UnpackedRef::Double(f) => pack_number(packed, FLOAT64, &f.to_be_bytes())?,
+        UnpackedRef::Bool(false) => packed.write_all(&[FALSE])?,
+        UnpackedRef::Bool(true) => packed.write_all(&[TRUE])?,
+        UnpackedRef::Null => packed.write_all(&[NIL])?,
+        UnpackedRef::Undefined if undefined_as_nil => packed.write_all(&[NIL])?,
+        UnpackedRef::Undefined => return Err(ErrorKind::NoEquivalent.into()),
+        UnpackedRef::Raw(bytes) => {
+            pack_len(packed, bytes.len(), None, Some(BIN8), BIN16, BIN32)?;
+            packed.write_all(bytes)?;
+        }
+        UnpackedRef::String(s) => {
+            pack_len(
+                packed,
+                s.len(),
+                Some((0xa0, 0x1f)),
+                Some(STR8),
+                STR16,
+                STR32,
+            )?;
+            packed.write_all(s.as_bytes())?;
+        }
+        UnpackedRef::Array(_) | UnpackedRef::Map(_) => {
+            return Err(ErrorKind::Message("unexpected container in a token".to_string()).into())
+        }
+    }
+    Ok(())
+}
+
+fn pack_number<W: Write>(packed: &mut W, type_: u8, bytes: &[u8]) -> Result<()> {
+    packed.write_all(&[type_])?;
+    packed.write_all(bytes)?;
+    Ok(())
+}
+
+/// Writes a value as MessagePack to `writer`
+///
+/// `Undefined` is written as nil if `undefined_as_nil` is set, and otherwise fails with
+/// `ErrorKind::NoEquivalent` after the part of the value before it has been written.
+pub fn pack_to<W: Write>(writer: &mut W, value: &Unpacked, undefined_as_nil: bool) -> Result<()> {
+    value.visit_tokens(&mut |token| pack_token(writer, token, undefined_as_nil))
+}
+
+/// Packs a value as MessagePack, failing with `ErrorKind::NoEquivalent` if it holds `Undefined`
+pub fn pack(value: &Unpacked) -> Result<Vec<u8>> {
+    let mut packed = vec![];
+    pack_to(&mut packed, value, false)?;
+    Ok(packed)
+}
+
+/// Creates an unpacker that reads MessagePack values from the start of `data` using `options`
+pub fn unpacker(data: &[u8], options: UnpackOptions) -> Unpacker<'_> {
+    Unpacker::with_options(data, options).with_format(Format::MessagePack)
+}
+
+/// Unpacks data in the MessagePack format
+pub fn unpack(data: &[u8]) -> Result<Unpacked> {
+    unpacker(data, UnpackOptions::default()).unpack()
+}
+
+/// Unpacks data in the MessagePack format, borrowing strings and raw bytes from `data`
+pub fn unpack_ref(data: &[u8]) -> Result<UnpackedRef<'_>> {
+    unpacker(data, UnpackOptions::default()).unpack_ref()
+}
+
+/// Unpacks data in the MessagePack format using `options`
+///
+/// In strict mode this fails if any bytes follow the value.
+pub fn unpack_with_options(data: &[u8], options: &UnpackOptions) -> Result<Unpacked> {
+    let mut unpacker = unpacker(data, options.clone());
+    let value = unpacker.unpack()?;
+    unpacker.check_end()?;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::PathSegment;
+
+    #[test]
+    fn unpack_spec() {
+        let data = [
+            0x82, 0xa7, b'c', b'o', b'm', b'p', b'a', b'c', b't', 0xc3, 0xa6, b's', b'c', b'h',
+            b'e', b'm', b'a', 0x00,
+        ];
+        assert_eq!(
+            unpack(&data).unwrap(),
+            binarypack!({ "compact": true, "schema": 0u8 })
+        );
+
+        let cases: Vec<(Vec<u8>, Unpacked)> = vec![
+            (vec![0xc4, 2, 1, 2], Unpacked::Raw(vec![1, 2])),
+            (vec![0xc5, 0, 1, 3], Unpacked::Raw(vec![3])),
+            (vec![0xc6, 0, 0, 0, 0], Unpacked::Raw(vec![])),
+            (vec![0xd9, 1, b'a'], "a".into()),
+            (vec![0xda, 0, 1, b'b'], "b".into()),
+            (vec![0xdb, 0, 0, 0, 1, b'c'], "c".into()),
+            (vec![0xa1, b'd'], "d".into()),
+            (vec![0xdc, 0, 1, 0xff], binarypack!([-1i8])),
+            (vec![0xde, 0, 1, 0xc0, 0xc2], binarypack!({ null: false })),
+            (
+                vec![0xcb, 0x3f, 0xf8, 0, 0, 0, 0, 0, 0],
+                Unpacked::Double(1.5),
+            ),
+            (vec![0xd1, 0xfe, 0xd4], Unpacked::Int16(-300)),
+        ];
+        for (data, expected) in cases {
+            assert_eq!(unpack(&data).unwrap(), expected, "{:02x?}", data);
+        }
+
+        let mut s = vec![0xb4];
+        s.extend_from_slice(&[b'x'; 20]);
+        assert_eq!(unpack(&s).unwrap(), Unpacked::String("x".repeat(20)));
+        assert_eq!(unpack_ref(&[0xc4, 1, 7]).unwrap(), UnpackedRef::Raw(&[7]));
+    }
+
+    #[test]
+    fn pack_values() {
+        let value = binarypack!({
+            "text": "a string longer than thirty one bytes",
+            "raw": b"\x00\x01",
+            "numbers": [1u8, 200u8, -1i8, -100i8, 1000u16, -70000i32, 1.5, Unpacked::Float(0.5)],
+            "nested": [[], {}, { 1: [null, true, false] }]
+        });
+        let packed = pack(&value).unwrap();
+        assert_eq!(unpack(&packed).unwrap(), value);
+        assert_eq!(
+            pack(&binarypack!(["ab"])).unwrap(),
+            vec![0x91, 0xa2, b'a', b'b']
+        );
+
+        let value = binarypack!([1u8, undefined]);
+        let e = pack(&value).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::NoEquivalent));
+        let mut packed = vec![];
+        pack_to(&mut packed, &value, true).unwrap();
+        assert_eq!(packed, vec![0x92, 0x01, 0xc0]);
+    }
+
+    #[test]
+    fn extensions() {
+        let data = [0x93, 0xd4, 1, 0xaa, 0xc7, 2, 0xff, 0, 0, 0xc1];
+        let mut unpacker = unpacker(&data, UnpackOptions::default());
+        match unpacker.unpack().unwrap() {
+            Unpacked::Array(v) => {
+                assert_eq!(v.len(), 3);
+                assert!(v.iter().all(|x| matches!(x, Unpacked::Undefined)));
+            }
+            value => panic!("{:?}", value),
+        }
+        let warnings = unpacker.take_warnings();
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(warnings[0].kind(), &WarningKind::SkippedExtension(1));
+        assert_eq!(warnings[0].offset(), 1);
+        assert_eq!(warnings[1].kind(), &WarningKind::SkippedExtension(-1));
+        assert_eq!(warnings[1].offset(), 4);
+
+        let e = unpack(&[0xd8, 5, 0, 0]).unwrap_err();
+        assert!(e.is_end_of_data());
+    }
+
+    #[test]
+    fn strict() {
+        let options = UnpackOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let e = unpack_with_options(&[0x91, 0xd4, 1, 0xaa], &options).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::Extension(1)));
+        assert_eq!(e.offset(), Some(1));
+        assert_eq!(e.path(), &[PathSegment::Index(0)]);
+
+        let e = unpack_with_options(&[0xc1], &options).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::ReservedType));
+
+        for data in [
+            vec![0xd9, 1, b'a'],
+            vec![0xda, 0, 1, b'a'],
+            vec![0xc5, 0, 0],
+            vec![0xdc, 0, 0],
+            vec![0xcc, 1],
+        ] {
+            let e = unpack_with_options(&data, &options).unwrap_err();
+            assert!(
+                matches!(e.kind(), ErrorKind::NonMinimalEncoding),
+                "{:02x?}",
+                data
+            );
+            unpack(&data).unwrap();
+        }
+
+        let e = unpack_with_options(&[0xc0, 0xc0], &options).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::TrailingData));
+        unpack_with_options(&[0xc4, 0], &options).unwrap();
+    }
+}
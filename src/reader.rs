@@ -0,0 +1,174 @@
+use std::io::{ErrorKind, Read};
+
+use crate::binarypack::{UnpackOptions, Unpacked};
+use crate::decoder::{Decoder, Status};
+use crate::error::{Error, Result, Warning};
+
+const CHUNK_SIZE: usize = 8192;
+
+/// Unpacks js-binarypack values from any `std::io::Read`
+///
+/// Input is read in chunks as it is needed, so a value is never required to be in memory as a
+/// whole before decoding starts. Bytes read past the end of one value are kept for the next.
+pub struct Reader<R: Read> {
+    inner: R,
+    decoder: Decoder,
+    chunk: Vec<u8>,
+    failed: bool,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(inner: R) -> Reader<R> {
+        Reader::with_options(inner, UnpackOptions::default())
+    }
+
+    pub fn with_options(inner: R, options: UnpackOptions) -> Reader<R> {
+        Reader {
+            inner,
+            decoder: Decoder::with_options(options),
+            chunk: vec![0; CHUNK_SIZE],
+            failed: false,
+        }
+    }
+
+    /// Returns a reference to the underlying reader
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// The problems repaired in the values read so far, as set by `UnpackOptions::invalid_utf8`
+    /// and `UnpackOptions::js_numbers`
+    pub fn warnings(&self) -> &[Warning] {
+        self.decoder.warnings()
+    }
+
+    /// Returns the warnings recorded so far, and clears them
+    pub fn take_warnings(&mut self) -> Vec<Warning> {
+        self.decoder.take_warnings()
+    }
+
+    /// Unpacks the next value, failing with an end of data error if the input has ended
+    pub fn unpack(&mut self) -> Result<Unpacked> {
+        let offset = self.decoder.offset();
+        self.next_value()?
+            .ok_or_else(|| Error::end_of_data(1, 0).at(offset, None))
+    }
+
+    /// Unpacks the next value, or returns `None` if the input ended cleanly between values
+    fn next_value(&mut self) -> Result<Option<Unpacked>> {
+        loop {
+            if let Status::Complete(value) = self.decoder.decode()? {
+                return Ok(Some(value));
+            }
+
+            let n = match self.inner.read(&mut self.chunk) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if n == 0 {
+                self.decoder.finish()?;
+                return Ok(None);
+            }
+            self.decoder.feed(&self.chunk[..n]);
+        }
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = Result<Unpacked>;
+
+    /// Unpacks the next value, returning `None` once the input ends cleanly or after the first
+    /// error.
+    fn next(&mut self) -> Option<Result<Unpacked>> {
+        if self.failed {
+            return None;
+        }
+
+        let result = self.next_value().transpose();
+        self.failed = matches!(result, Some(Err(_)));
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::{ErrorKind, PathSegment};
+    use std::io;
+
+    /// A reader that hands out its data a few bytes at a time
+    struct Trickle<'a> {
+        data: &'a [u8],
+    }
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.data.len()).min(3);
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn read_values() {
+        let mut packed = Unpacked::String("hello world!".to_string()).pack();
+        packed.extend(Unpacked::Array(vec![Unpacked::Null, Unpacked::Uint32(7)]).pack());
+
+        let values: Vec<_> = Reader::new(Trickle { data: &packed })
+            .map(|v| v.unwrap())
+            .collect();
+        assert_eq!(
+            values,
+            vec!(
+                Unpacked::String("hello world!".to_string()),
+                Unpacked::Array(vec![Unpacked::Null, Unpacked::Uint32(7)])
+            )
+        );
+    }
+
+    #[test]
+    fn read_truncated() {
+        let packed = [0x92, 1];
+        let mut reader = Reader::new(&packed[..]);
+        let e = reader.unpack().unwrap_err();
+        assert!(e.is_end_of_data());
+        assert_eq!(e.offset(), Some(2));
+        assert_eq!(e.path(), &[PathSegment::Index(1)]);
+
+        let mut reader = Reader::new(&[][..]);
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn iter_stops_after_error() {
+        let values: Vec<_> = Reader::new(&[0x01, 0x92, 1][..]).collect();
+        assert_eq!(values.len(), 2);
+        assert_eq!(*values[0].as_ref().unwrap(), Unpacked::Uint8(1));
+        assert!(values[1].as_ref().unwrap_err().is_end_of_data());
+
+        let values: Vec<_> = Reader::new(Trickle {
+            data: &[0xb1, 0xff, 0x01],
+        })
+        .collect();
+        assert_eq!(values.len(), 1);
+        assert!(matches!(
+            values[0].as_ref().unwrap_err().kind(),
+            ErrorKind::StringParseError
+        ));
+    }
+
+    #[test]
+    fn read_error() {
+        struct Broken;
+        impl Read for Broken {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("broken"))
+            }
+        }
+
+        let e = Reader::new(Broken).unpack().unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::Io(_)));
+    }
+}
@@ -0,0 +1,638 @@
+//! Serialize Rust data structures into the js-binarypack format using serde
+//!
+//! Values are encoded exactly as the equivalent `Unpacked` tree would be by `Unpacked::pack`:
+//!
+//! * structs and maps become `Map`s, with struct fields keyed by their names
+//! * sequences, tuples and tuple structs become `Array`s
+//! * `None`, `()` and unit structs become `Null`, while `Some(v)` and newtype structs are
+//!   encoded as their contents
+//! * enums are externally tagged: a unit variant is the `String` of its name, and any other
+//!   variant is a single entry `Map` from its name to its contents
+//! * byte slices become `Raw` and `char`s become one character `String`s
+//!
+//! `PackOptions::compact`, `PackOptions::narrow_integers` and `PackOptions::js_numbers` are
+//! honoured, but `PackOptions::canonical` is not: map entries are written in the order they are
+//! serialized.
+
+use std::io::Write;
+
+use serde::ser::{self, Serialize, SerializeMap, SerializeSeq};
+
+use crate::binarypack::{PackOptions, Unpacked};
+use crate::error::{Error, Result};
+
+/// The unit struct name `Unpacked::Undefined` is serialized under, which this crate's
+/// `Serializer` and `Deserializer` recognise so that it is not mistaken for `Null`
+pub(crate) const UNDEFINED: &str = "$binarypack::Undefined";
+
+/// A serde `Serializer` that writes the js-binarypack format into a `std::io::Write`
+pub struct Serializer<W: Write> {
+    writer: W,
+    options: PackOptions,
+}
+
+impl<W: Write> Serializer<W> {
+    pub fn new(writer: W) -> Serializer<W> {
+        Serializer::with_options(writer, PackOptions::default())
+    }
+
+    pub fn with_options(writer: W, options: PackOptions) -> Serializer<W> {
+        Serializer { writer, options }
+    }
+
+    /// Returns the underlying writer
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn pack(&mut self, value: Unpacked) -> Result<()> {
+        value._pack(&mut self.writer, &self.options)?;
+        Ok(())
+    }
+}
+
+/// Serializes a value into the js-binarypack format, writing it to `writer`
+pub fn to_writer<W: Write, T: Serialize + ?Sized>(writer: W, value: &T) -> Result<()> {
+    value.serialize(&mut Serializer::new(writer))
+}
+
+/// Serializes a value into a new `Vec<u8>` in the js-binarypack format
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    let mut packed = vec![];
+    to_writer(&mut packed, value)?;
+    Ok(packed)
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Array,
+    Map,
+}
+
+/// Serializes the elements of an array or map
+///
+/// The length of a container has to be written before its elements. When serde doesn't know
+/// the length up front, elements are encoded into a buffer and counted, and the header is
+/// written once the container is complete.
+pub struct Compound<'a, W: Write> {
+    ser: &'a mut Serializer<W>,
+    kind: Kind,
+    buffer: Option<Vec<u8>>,
+    count: usize,
+}
+
+impl<'a, W: Write> Compound<'a, W> {
+    fn new(ser: &'a mut Serializer<W>, kind: Kind, len: Option<usize>) -> Result<Self> {
+        let buffer = match len {
+            Some(len) => {
+                match kind {
+                    Kind::Array => Unpacked::_pack_array_len(&mut ser.writer, len, &ser.options)?,
+                    Kind::Map => Unpacked::_pack_map_len(&mut ser.writer, len, &ser.options)?,
+                }
+                None
+            }
+            None => Some(vec![]),
+        };
+
+        Ok(Compound {
+            ser,
+            kind,
+            buffer,
+            count: 0,
+        })
+    }
+
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        match &mut self.buffer {
+            Some(buffer) => value.serialize(&mut Serializer::with_options(
+                buffer,
+                self.ser.options.clone(),
+            )),
+            None => value.serialize(&mut *self.ser),
+        }
+    }
+
+    fn finish(self) -> Result<()> {
+        if let Some(buffer) = self.buffer {
+            let writer = &mut self.ser.writer;
+            let options = &self.ser.options;
+            match self.kind {
+                Kind::Array => Unpacked::_pack_array_len(writer, self.count, options)?,
+                Kind::Map => Unpacked::_pack_map_len(writer, self.count, options)?,
+            }
+            writer.write_all(&buffer)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, W: Write> ser::Serializer for &'a mut Serializer<W> {
+    type Ok = ();
+    type Error = Error;
+
+    type SerializeSeq = Compound<'a, W>;
+    type SerializeTuple = Compound<'a, W>;
+    type SerializeTupleStruct = Compound<'a, W>;
+    type SerializeTupleVariant = Compound<'a, W>;
+    type SerializeMap = Compound<'a, W>;
+    type SerializeStruct = Compound<'a, W>;
+    type SerializeStructVariant = Compound<'a, W>;
+
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        self.pack(Unpacked::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<()> {
+        self.pack(Unpacked::Int8(v))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<()> {
+        self.pack(Unpacked::Int16(v))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<()> {
+        self.pack(Unpacked::Int32(v))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<()> {
+        self.pack(Unpacked::Int64(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<()> {
+        self.pack(Unpacked::Uint8(v))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<()> {
+        self.pack(Unpacked::Uint16(v))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<()> {
+        self.pack(Unpacked::Uint32(v))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<()> {
+        self.pack(Unpacked::Uint64(v))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<()> {
+        self.pack(Unpacked::Float(v))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<()> {
+        self.pack(Unpacked::Double(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<()> {
+        self.serialize_str(v.encode_utf8(&mut [0u8; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<()> {
+        Unpacked::_pack_str(&mut self.writer, v, &self.options)?;
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        Unpacked::_pack_raw(&mut self.writer, v, &self.options)?;
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<()> {
+        self.pack(Unpacked::Null)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<()> {
+        self.pack(Unpacked::Null)
+    }
+
+    fn serialize_unit_struct(self, name: &'static str) -> Result<()> {
+        if name == UNDEFINED {
+            self.pack(Unpacked::Undefined)
+        } else {
+            self.serialize_unit()
+        }
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<()> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        Unpacked::_pack_map_len(&mut self.writer, 1, &self.options)?;
+        self.serialize_str(variant)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Compound<'a, W>> {
+        Compound::new(self, Kind::Array, len)
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Compound<'a, W>> {
+        Compound::new(self, Kind::Array, Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<Compound<'a, W>> {
+        Compound::new(self, Kind::Array, Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Compound<'a, W>> {
+        Unpacked::_pack_map_len(&mut self.writer, 1, &self.options)?;
+        Unpacked::_pack_str(&mut self.writer, variant, &self.options)?;
+        Compound::new(self, Kind::Array, Some(len))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Compound<'a, W>> {
+        Compound::new(self, Kind::Map, len)
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Compound<'a, W>> {
+        Compound::new(self, Kind::Map, Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Compound<'a, W>> {
+        Unpacked::_pack_map_len(&mut self.writer, 1, &self.options)?;
+        Unpacked::_pack_str(&mut self.writer, variant, &self.options)?;
+        Compound::new(self, Kind::Map, Some(len))
+    }
+}
+
+impl<'a, W: Write> ser::SerializeSeq for Compound<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.count += 1;
+        self.element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<'a, W: Write> ser::SerializeTuple for Compound<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<'a, W: Write> ser::SerializeTupleStruct for Compound<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<'a, W: Write> ser::SerializeTupleVariant for Compound<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<'a, W: Write> ser::SerializeMap for Compound<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        self.count += 1;
+        self.element(key)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<'a, W: Write> ser::SerializeStruct for Compound<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        ser::SerializeMap::serialize_entry(self, key, value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<'a, W: Write> ser::SerializeStructVariant for Compound<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        ser::SerializeMap::serialize_entry(self, key, value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+/// Serializes an `Unpacked` tree into any serde format
+///
+/// Each variant maps onto the serde data model as follows:
+///
+/// * `Uint8` through `Int64` become integers of the same width, and `Float` and `Double` become
+///   `f32` and `f64`
+/// * `Raw` becomes bytes; formats without a byte type, such as JSON, write an array of numbers
+/// * `Null` becomes `None` and `Undefined` becomes a unit struct with a reserved name; formats
+///   with a single null value, such as JSON, write both as their null, while this one keeps them
+///   apart
+/// * `Array` becomes a sequence and `Map` a map, whose keys may be any value
+impl Serialize for Unpacked {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            Unpacked::Uint8(a) => serializer.serialize_u8(*a),
+            Unpacked::Uint16(a) => serializer.serialize_u16(*a),
+            Unpacked::Uint32(a) => serializer.serialize_u32(*a),
+            Unpacked::Uint64(a) => serializer.serialize_u64(*a),
+            Unpacked::Int8(a) => serializer.serialize_i8(*a),
+            Unpacked::Int16(a) => serializer.serialize_i16(*a),
+            Unpacked::Int32(a) => serializer.serialize_i32(*a),
+            Unpacked::Int64(a) => serializer.serialize_i64(*a),
+            Unpacked::Float(f) => serializer.serialize_f32(*f),
+            Unpacked::Double(f) => serializer.serialize_f64(*f),
+            Unpacked::Bool(b) => serializer.serialize_bool(*b),
+            Unpacked::Raw(bytes) => serializer.serialize_bytes(bytes),
+            Unpacked::String(s) => serializer.serialize_str(s),
+            Unpacked::Null => serializer.serialize_none(),
+            Unpacked::Undefined => serializer.serialize_unit_struct(UNDEFINED),
+            Unpacked::Array(v) => {
+                let mut seq = serializer.serialize_seq(Some(v.len()))?;
+                for element in v {
+                    seq.serialize_element(element)?;
+                }
+                seq.end()
+            }
+            Unpacked::Map(m) => {
+                let mut map = serializer.serialize_map(Some(m.len()))?;
+                for (key, value) in m {
+                    map.serialize_entry(key, value)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::ErrorKind;
+    use serde::Serialize;
+    use std::collections::HashMap;
+
+    fn string(s: &str) -> Unpacked {
+        Unpacked::String(s.to_string())
+    }
+
+    #[test]
+    fn serialize_scalars() {
+        assert_eq!(to_vec(&7u8).unwrap(), Unpacked::Uint8(7).pack());
+        assert_eq!(to_vec(&258u16).unwrap(), Unpacked::Uint16(258).pack());
+        assert_eq!(to_vec(&-3i64).unwrap(), Unpacked::Int64(-3).pack());
+        assert_eq!(to_vec(&-31i8).unwrap(), Unpacked::Int8(-31).pack());
+        assert_eq!(to_vec(&0.5f32).unwrap(), Unpacked::Float(0.5).pack());
+        assert_eq!(to_vec(&0.5f64).unwrap(), Unpacked::Double(0.5).pack());
+        assert_eq!(to_vec(&true).unwrap(), Unpacked::Bool(true).pack());
+        assert_eq!(to_vec("hi").unwrap(), string("hi").pack());
+        assert_eq!(to_vec(&'x').unwrap(), string("x").pack());
+        assert_eq!(to_vec(&None::<u8>).unwrap(), Unpacked::Null.pack());
+        assert_eq!(to_vec(&Some(1u8)).unwrap(), Unpacked::Uint8(1).pack());
+        assert_eq!(to_vec(&()).unwrap(), Unpacked::Null.pack());
+    }
+
+    #[test]
+    fn serialize_struct() {
+        #[derive(Serialize)]
+        struct Inner(u32);
+
+        #[derive(Serialize)]
+        struct Message {
+            id: u16,
+            tags: Vec<String>,
+            inner: Inner,
+            pair: (i32, bool),
+        }
+
+        let message = Message {
+            id: 1,
+            tags: vec!["a".to_string()],
+            inner: Inner(2),
+            pair: (-4, false),
+        };
+        // Single entry maps have only one possible encoding, so compare the decoded trees
+        let unpacked = crate::binarypack::unpack(&to_vec(&message).unwrap()).unwrap();
+
+        let mut m = HashMap::new();
+        m.insert(string("id"), Unpacked::Uint16(1));
+        m.insert(string("tags"), Unpacked::Array(vec![string("a")]));
+        m.insert(string("inner"), Unpacked::Uint32(2));
+        m.insert(
+            string("pair"),
+            Unpacked::Array(vec![Unpacked::Int32(-4), Unpacked::Bool(false)]),
+        );
+        assert_eq!(unpacked, Unpacked::Map(m));
+    }
+
+    #[test]
+    fn serialize_enum() {
+        #[derive(Serialize)]
+        enum E {
+            Unit,
+            Newtype(u8),
+            Tuple(u8, u8),
+            Struct { a: u8 },
+        }
+
+        let variant = |name: &str, value: Unpacked| {
+            let mut m = HashMap::new();
+            m.insert(string(name), value);
+            Unpacked::Map(m).pack()
+        };
+
+        assert_eq!(to_vec(&E::Unit).unwrap(), string("Unit").pack());
+        assert_eq!(
+            to_vec(&E::Newtype(1)).unwrap(),
+            variant("Newtype", Unpacked::Uint8(1))
+        );
+        assert_eq!(
+            to_vec(&E::Tuple(1, 2)).unwrap(),
+            variant(
+                "Tuple",
+                Unpacked::Array(vec![Unpacked::Uint8(1), Unpacked::Uint8(2)])
+            )
+        );
+        let mut m = HashMap::new();
+        m.insert(string("a"), Unpacked::Uint8(3));
+        assert_eq!(
+            to_vec(&E::Struct { a: 3 }).unwrap(),
+            variant("Struct", Unpacked::Map(m))
+        );
+    }
+
+    #[test]
+    fn serialize_unpacked() {
+        let values = vec![
+            Unpacked::Uint8(200),
+            Unpacked::Int64(-9),
+            Unpacked::Float(0.5),
+            Unpacked::Raw(vec![1, 2, 3]),
+            Unpacked::String("abc".to_string()),
+            Unpacked::Null,
+            Unpacked::Undefined,
+            Unpacked::Array(vec![Unpacked::Bool(false), Unpacked::Uint16(7)]),
+        ];
+        for value in values {
+            assert_eq!(to_vec(&value).unwrap(), value.pack());
+        }
+
+        let mut m = HashMap::new();
+        m.insert(
+            string("raw"),
+            Unpacked::Array(vec![Unpacked::Raw(vec![1, 2]), Unpacked::Undefined]),
+        );
+        assert_eq!(
+            serde_json::to_string(&Unpacked::Map(m)).unwrap(),
+            r#"{"raw":[[1,2],null]}"#
+        );
+    }
+
+    #[test]
+    fn serialize_unknown_length() {
+        struct Unsized;
+
+        impl Serialize for Unsized {
+            fn serialize<S: ser::Serializer>(
+                &self,
+                serializer: S,
+            ) -> std::result::Result<S::Ok, S::Error> {
+                let mut map = serializer.serialize_map(None)?;
+                map.serialize_entry("a", &vec![1u8, 2u8])?;
+                map.end()
+            }
+        }
+
+        let mut m = HashMap::new();
+        m.insert(
+            string("a"),
+            Unpacked::Array(vec![Unpacked::Uint8(1), Unpacked::Uint8(2)]),
+        );
+        assert_eq!(to_vec(&Unsized).unwrap(), Unpacked::Map(m).pack());
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn too_large() {
+        struct Huge;
+
+        impl Serialize for Huge {
+            fn serialize<S: ser::Serializer>(
+                &self,
+                serializer: S,
+            ) -> std::result::Result<S::Ok, S::Error> {
+                serializer.serialize_seq(Some(1 << 32))?.end()
+            }
+        }
+
+        let e = to_vec(&Huge).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::TooLarge(_)));
+    }
+
+    #[test]
+    fn compact() {
+        #[derive(Serialize)]
+        struct Message {
+            id: u8,
+            tags: Vec<&'static str>,
+        }
+
+        let options = PackOptions {
+            compact: true,
+            ..Default::default()
+        };
+        let message = Message {
+            id: 1,
+            tags: vec!["a"],
+        };
+        let mut ser = Serializer::with_options(vec![], options.clone());
+        message.serialize(&mut ser).unwrap();
+        let packed = ser.into_inner();
+        assert_eq!(packed[0], 0x82);
+        assert_eq!(
+            crate::binarypack::unpack(&packed).unwrap(),
+            binarypack!({ "id": 1u8, "tags": ["a"] })
+        );
+
+        let mut ser = Serializer::with_options(vec![], options);
+        ser::Serializer::collect_seq(&mut ser, (0..2u8).filter(|_| true)).unwrap();
+        assert_eq!(ser.into_inner(), vec![0x92, 0, 1]);
+    }
+}
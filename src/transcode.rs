@@ -0,0 +1,314 @@
+//! Convert between js-binarypack and MessagePack, and from js-binarypack to CBOR, without
+//! building `Unpacked` values
+//!
+//! A `Transcoder` reads its input a token at a time and writes each token in the other format
+//! as soon as it is read, so the only memory used is a counter for each array or map it is
+//! inside of. Values are mapped as follows:
+//!
+//! * binarypack raw and MessagePack bin convert to each other, as do the str forms of both
+//! * `Undefined` has no MessagePack equivalent, and fails with `ErrorKind::NoEquivalent`
+//!   unless `TranscodeOptions::undefined_as_nil` is set
+//! * MessagePack extension values have no binarypack equivalent, and fail with
+//!   `ErrorKind::Extension`
+//! * type bytes that js-binarypack reserves fail with `ErrorKind::ReservedType`, as they do in
+//!   `validate`, rather than being read as `Undefined`
+//! * integers and floats keep the width they were read with
+//!
+//! See the `msgpack` module for how the type bytes of js-binarypack and MessagePack differ, and
+//! the `cbor` module for how values are written as CBOR. Every js-binarypack value, `Undefined`
+//! included, has a CBOR equivalent.
+
+use std::io::Write;
+
+use crate::binarypack::{is_reserved, PackOptions, UnpackOptions, Unpacked, Unpacker};
+use crate::cbor;
+use crate::error::{ErrorKind, Result, Warning};
+use crate::msgpack;
+
+/// Choices that apply while transcoding
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TranscodeOptions {
+    /// Limits and behaviour that apply while reading the input
+    pub unpack: UnpackOptions,
+    /// How js-binarypack output is written. `canonical` is ignored, since map entries are
+    /// written in the order they are read.
+    pub pack: PackOptions,
+    /// Writes `Undefined` as MessagePack nil instead of failing
+    pub undefined_as_nil: bool,
+}
+
+/// The format a `Transcoder` writes
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Target {
+    Binarypack,
+    MessagePack,
+    Cbor,
+}
+
+/// Converts a buffer of values from one format to the other
+///
+/// A buffer may hold several values back to back. Each call to `transcode` converts the next
+/// one, and `offset`/`is_empty` report how much of the input has been consumed so far.
+pub struct Transcoder<'a> {
+    unpacker: Unpacker<'a>,
+    target: Target,
+    options: TranscodeOptions,
+}
+
+impl<'a> Transcoder<'a> {
+    /// Creates a transcoder that converts the js-binarypack values in `data` to MessagePack
+    pub fn binarypack_to_msgpack(data: &'a [u8], options: TranscodeOptions) -> Transcoder<'a> {
+        Transcoder {
+            unpacker: Unpacker::with_options(data, options.unpack.clone()),
+            target: Target::MessagePack,
+            options,
+        }
+    }
+
+    /// Creates a transcoder that converts the MessagePack values in `data` to js-binarypack
+    pub fn msgpack_to_binarypack(data: &'a [u8], options: TranscodeOptions) -> Transcoder<'a> {
+        Transcoder {
+            unpacker: msgpack::unpacker(data, options.unpack.clone()),
+            target: Target::Binarypack,
+            options,
+        }
+    }
+
+    /// Creates a transcoder that converts the js-binarypack values in `data` to CBOR
+    ///
+    /// `TranscodeOptions::pack` and `undefined_as_nil` do not apply.
+    pub fn binarypack_to_cbor(data: &'a [u8], options: TranscodeOptions) -> Transcoder<'a> {
+        Transcoder {
+            unpacker: Unpacker::with_options(data, options.unpack.clone()),
+            target: Target::Cbor,
+            options,
+        }
+    }
+
+    /// The number of bytes of the input consumed by the values converted so far
+    pub fn offset(&self) -> usize {
+        self.unpacker.offset()
+    }
+
+    /// Returns true if every byte of the input has been consumed
+    pub fn is_empty(&self) -> bool {
+        self.unpacker.is_empty()
+    }
+
+    /// The problems repaired in the values converted so far, as set by `TranscodeOptions::unpack`
+    pub fn warnings(&self) -> &[Warning] {
+        self.unpacker.warnings()
+    }
+
+    /// Converts the next value, writing it to `out`
+    ///
+    /// On failure part of the value may already have been written, and errors record the offset
+    /// and type byte of the input that could not be converted, but not its path. The transcoder
+    /// is left at the start of the value, so `offset` does not move.
+    pub fn transcode<W: Write>(&mut self, out: &mut W) -> Result<()> {
+        let target = self.target;
+        let options = &self.options;
+        self.unpacker.walk(|unpacker, token, _| {
+            // Every target but js-binarypack is written from js-binarypack input
+            if target != Target::Binarypack && is_reserved(unpacker.token_type()) {
+                return Err(ErrorKind::ReservedType.into());
+            }
+            match target {
+                Target::MessagePack => msgpack::pack_token(out, &token, options.undefined_as_nil),
+                Target::Cbor => cbor::pack_token(out, &token),
+                Target::Binarypack => Unpacked::_pack_token(out, &token, &options.pack),
+            }
+        })
+    }
+
+    /// Converts every value left in the input, writing them to `out`
+    pub fn transcode_all<W: Write>(&mut self, out: &mut W) -> Result<()> {
+        while !self.is_empty() {
+            self.transcode(out)?;
+        }
+        Ok(())
+    }
+}
+
+/// Converts every js-binarypack value in `data` to MessagePack
+pub fn binarypack_to_msgpack(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = vec![];
+    Transcoder::binarypack_to_msgpack(data, TranscodeOptions::default()).transcode_all(&mut out)?;
+    Ok(out)
+}
+
+/// Converts every MessagePack value in `data` to js-binarypack
+pub fn msgpack_to_binarypack(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = vec![];
+    Transcoder::msgpack_to_binarypack(data, TranscodeOptions::default()).transcode_all(&mut out)?;
+    Ok(out)
+}
+
+/// Converts every js-binarypack value in `data` to CBOR
+pub fn binarypack_to_cbor(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = vec![];
+    Transcoder::binarypack_to_cbor(data, TranscodeOptions::default()).transcode_all(&mut out)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::binarypack::unpack;
+    use crate::error::ErrorKind;
+
+    #[test]
+    fn round_trip() {
+        let value = binarypack!({
+            "text": "a string longer than thirty one bytes",
+            "raw": b"\x00\x01",
+            "numbers": [1u8, 200u8, -1i8, -100i8, 1000u16, -70000i32, 1.5, Unpacked::Float(0.5)],
+            "nested": [[], {}, { 1: [null, true, false] }]
+        });
+        let packed = value.pack();
+        let converted = binarypack_to_msgpack(&packed).unwrap();
+        assert_eq!(msgpack::unpack(&converted).unwrap(), value);
+        assert_eq!(msgpack_to_binarypack(&converted).unwrap(), packed);
+    }
+
+    #[test]
+    fn forms() {
+        let cases: Vec<(Unpacked, Vec<u8>)> = vec![
+            (Unpacked::Raw(vec![7]), vec![0xc4, 1, 7]),
+            ("ab".into(), vec![0xa2, b'a', b'b']),
+            (Unpacked::String("x".repeat(32)), {
+                let mut v = vec![0xd9, 32];
+                v.extend_from_slice(&[b'x'; 32]);
+                v
+            }),
+            (Unpacked::Uint32(1), vec![0xce, 0, 0, 0, 1]),
+            (binarypack!([1u8]), vec![0x91, 0x01]),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(binarypack_to_msgpack(&value.pack()).unwrap(), expected);
+        }
+
+        let data = binarypack!(["a"]).pack();
+        let options = TranscodeOptions {
+            pack: PackOptions {
+                compact: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut out = vec![];
+        Transcoder::msgpack_to_binarypack(&binarypack_to_msgpack(&data).unwrap(), options)
+            .transcode(&mut out)
+            .unwrap();
+        assert_eq!(out, vec![0x91, 0xb1, b'a']);
+    }
+
+    #[test]
+    fn no_equivalent() {
+        let packed = binarypack!([1, undefined]).pack();
+        let e = binarypack_to_msgpack(&packed).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::NoEquivalent));
+        assert_eq!(e.offset(), Some(8));
+        assert_eq!(e.type_byte(), Some(0xc1));
+
+        let options = TranscodeOptions {
+            undefined_as_nil: true,
+            ..Default::default()
+        };
+        let mut out = vec![];
+        Transcoder::binarypack_to_msgpack(&packed, options)
+            .transcode(&mut out)
+            .unwrap();
+        assert_eq!(msgpack::unpack(&out).unwrap(), binarypack!([1, null]));
+
+        let e = msgpack_to_binarypack(&[0x92, 0x01, 0xd5, 0x02, 0, 0]).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::Extension(2)));
+        assert_eq!(e.offset(), Some(2));
+        assert!(unpack(&msgpack_to_binarypack(&[0xc1]).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn reserved() {
+        for type_ in [0xc4, 0xc9, 0xd4, 0xd7] {
+            let data = [0x92, 0x01, type_];
+            let e = binarypack_to_msgpack(&data).unwrap_err();
+            assert!(matches!(e.kind(), ErrorKind::ReservedType));
+            assert_eq!(e.offset(), Some(2));
+            assert_eq!(e.type_byte(), Some(type_));
+            let e = binarypack_to_cbor(&data).unwrap_err();
+            assert!(matches!(e.kind(), ErrorKind::ReservedType));
+            assert_eq!(e.offset(), Some(2));
+        }
+
+        let options = TranscodeOptions {
+            undefined_as_nil: true,
+            ..Default::default()
+        };
+        let e = Transcoder::binarypack_to_msgpack(&[0xc5], options)
+            .transcode(&mut vec![])
+            .unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::ReservedType));
+    }
+
+    #[test]
+    fn to_cbor() {
+        let value = binarypack!({
+            "raw": b"\x00\x01",
+            "numbers": [1u8, 1000u16, -70000i32, Unpacked::Float(0.5), 1.5],
+            "nested": [[], { 1u8: [null, true, false] }]
+        });
+        let converted = binarypack_to_cbor(&value.pack()).unwrap();
+        assert_eq!(converted, cbor::pack(&value));
+        assert_eq!(cbor::unpack(&converted).unwrap(), value);
+
+        let mut data = binarypack!("a").pack();
+        data.extend(Unpacked::Uint16(2).pack());
+        assert_eq!(
+            binarypack_to_cbor(&data).unwrap(),
+            vec![0x61, b'a', 0x19, 0, 2]
+        );
+        assert_eq!(
+            binarypack_to_cbor(&binarypack!([undefined]).pack()).unwrap(),
+            vec![0x81, 0xf7]
+        );
+    }
+
+    #[test]
+    fn stream() {
+        let mut data = binarypack!("a").pack();
+        data.extend(binarypack!([1u8, 2u8]).pack());
+        let mut transcoder = Transcoder::binarypack_to_msgpack(&data, TranscodeOptions::default());
+        let mut out = vec![];
+        transcoder.transcode(&mut out).unwrap();
+        assert_eq!(out, vec![0xa1, b'a']);
+        assert_eq!(transcoder.offset(), 4);
+        transcoder.transcode(&mut out).unwrap();
+        assert_eq!(out, vec![0xa1, b'a', 0x92, 0x01, 0x02]);
+        assert!(transcoder.is_empty());
+
+        let options = TranscodeOptions {
+            unpack: UnpackOptions {
+                max_depth: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let data = binarypack!([[1u8]]).pack();
+        let e = Transcoder::binarypack_to_msgpack(&data, options)
+            .transcode_all(&mut vec![])
+            .unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::DepthLimitExceeded(1)));
+
+        let mut data = binarypack!("a").pack();
+        data.extend([0x92, 0x01, 0xc4]);
+        let mut transcoder = Transcoder::binarypack_to_msgpack(&data, TranscodeOptions::default());
+        transcoder.transcode(&mut vec![]).unwrap();
+        assert_eq!(transcoder.offset(), 4);
+        let e = transcoder.transcode(&mut vec![]).unwrap_err();
+        assert_eq!(e.offset(), Some(6));
+        assert_eq!(transcoder.offset(), 4);
+        assert!(!transcoder.is_empty());
+        assert!(transcoder.transcode(&mut vec![]).is_err());
+        assert_eq!(transcoder.offset(), 4);
+    }
+}
@@ -0,0 +1,112 @@
+use crate::binarypack::{is_reserved, UnpackOptions, Unpacker};
+use crate::error::{ErrorKind, Result};
+
+/// A summary of a value checked by `validate`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stats {
+    /// The number of values, counting every array and map as well as each of their elements,
+    /// keys and values
+    pub values: usize,
+    /// The number of arrays and maps around the most deeply nested value
+    pub max_depth: usize,
+    /// The number of bytes the value takes up
+    pub size: usize,
+}
+
+/// Checks that `data` starts with a well-formed value, without building it
+///
+/// Every length must fit in the input, every string must be valid UTF-8, and reserved type bytes
+/// are rejected rather than read as `Undefined`. Strings and raw bytes are never copied; the only
+/// memory used is a counter for each array or map the walk is inside of. Errors record the offset
+/// and type byte of the offending value, but not its path.
+pub fn validate(data: &[u8]) -> Result<Stats> {
+    validate_with_options(data, &UnpackOptions::default())
+}
+
+/// Checks that `data` starts with a well-formed value that also satisfies `options`
+///
+/// In strict mode this also fails if any bytes follow the value.
+pub fn validate_with_options(data: &[u8], options: &UnpackOptions) -> Result<Stats> {
+    let mut unpacker = Unpacker::with_options(data, options.clone());
+    let mut stats = Stats::default();
+    unpacker.walk(|unpacker, _, depth| {
+        if is_reserved(unpacker.token_type()) {
+            return Err(ErrorKind::ReservedType.into());
+        }
+        stats.values += 1;
+        stats.max_depth = stats.max_depth.max(depth);
+        Ok(())
+    })?;
+
+    unpacker.check_end()?;
+    stats.size = unpacker.offset();
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::binarypack::unpack;
+
+    #[test]
+    fn stats() {
+        assert_eq!(
+            validate(&[0xc0]).unwrap(),
+            Stats {
+                values: 1,
+                max_depth: 0,
+                size: 1
+            }
+        );
+
+        let packed = binarypack!({ "a": [1, [2, []]], "b": "text" }).pack();
+        let mut data = packed.clone();
+        data.extend(&[0xc0, 0xc4]);
+        assert_eq!(
+            validate(&data).unwrap(),
+            Stats {
+                values: 9,
+                max_depth: 3,
+                size: packed.len()
+            }
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        let e = validate(&[0x92, 0x01, 0xc5]).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::ReservedType));
+        assert_eq!(e.offset(), Some(2));
+        assert_eq!(e.type_byte(), Some(0xc5));
+        assert!(unpack(&[0x92, 0x01, 0xc5]).is_ok());
+
+        let e = validate(&[0x81, 0xd8, 0, 1, 0xff, 0x01]).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::StringParseError));
+        assert_eq!(e.offset(), Some(1));
+
+        assert!(validate(&[0xdd, 0xff, 0xff, 0xff, 0xff, 0x01])
+            .unwrap_err()
+            .is_end_of_data());
+        assert!(validate(&[]).unwrap_err().is_end_of_data());
+    }
+
+    #[test]
+    fn options() {
+        let packed = binarypack!([[[1]]]).pack();
+        let options = UnpackOptions {
+            max_depth: 2,
+            ..Default::default()
+        };
+        let e = validate_with_options(&packed, &options).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::DepthLimitExceeded(2)));
+
+        let options = UnpackOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let e = validate_with_options(&[0x81, 0x01, 0x02], &options).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::NonStringKey));
+        let e = validate_with_options(&[0xc0, 0xc0], &options).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::TrailingData));
+    }
+}
@@ -0,0 +1,148 @@
+use std::io::{BufWriter, Write};
+
+use crate::binarypack::{PackOptions, Unpacked};
+use crate::error::{Error, Result};
+
+const DEFAULT_CAPACITY: usize = 8192;
+
+/// Packs js-binarypack values into any `std::io::Write`
+///
+/// Output is buffered, so values are encoded straight into the sink without first being
+/// collected in a `Vec<u8>`. A `Writer` can be reused for any number of values; call `flush` to
+/// push out whatever is still buffered.
+pub struct Writer<W: Write> {
+    inner: BufWriter<W>,
+    options: PackOptions,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(inner: W) -> Writer<W> {
+        Writer::with_options(inner, PackOptions::default())
+    }
+
+    pub fn with_options(inner: W, options: PackOptions) -> Writer<W> {
+        Writer::with_capacity_and_options(DEFAULT_CAPACITY, inner, options)
+    }
+
+    /// Creates a writer with a buffer of at least `capacity` bytes
+    pub fn with_capacity(capacity: usize, inner: W) -> Writer<W> {
+        Writer::with_capacity_and_options(capacity, inner, PackOptions::default())
+    }
+
+    /// Creates a writer with a buffer of at least `capacity` bytes that packs values using
+    /// `options`
+    pub fn with_capacity_and_options(capacity: usize, inner: W, options: PackOptions) -> Writer<W> {
+        Writer {
+            inner: BufWriter::with_capacity(capacity, inner),
+            options,
+        }
+    }
+
+    /// Returns a reference to the underlying writer
+    pub fn get_ref(&self) -> &W {
+        self.inner.get_ref()
+    }
+
+    /// Packs a value into the buffer, writing to the sink whenever the buffer fills up
+    ///
+    /// If a string, raw, array or map in the value is too large for the format, this fails with
+    /// `ErrorKind::TooLarge` after the part of the value before it has been written.
+    pub fn pack(&mut self, value: &Unpacked) -> Result<()> {
+        value._pack(&mut self.inner, &self.options)?;
+        Ok(())
+    }
+
+    /// Writes all buffered bytes to the sink and flushes it
+    pub fn flush(&mut self) -> Result<()> {
+        self.inner.flush()?;
+        Ok(())
+    }
+
+    /// Flushes the buffer and returns the underlying writer
+    pub fn into_inner(self) -> Result<W> {
+        self.inner
+            .into_inner()
+            .map_err(|e| Error::from(e.into_error()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::ErrorKind;
+    use crate::reader::Reader;
+    use std::io;
+
+    #[test]
+    fn write_values() {
+        let values = vec![
+            Unpacked::String("hello world!".to_string()),
+            Unpacked::Array(vec![Unpacked::Null, Unpacked::Int32(-7)]),
+            Unpacked::Raw(vec![0; 10000]),
+        ];
+
+        let mut writer = Writer::with_capacity(16, vec![]);
+        for v in values.iter() {
+            writer.pack(v).unwrap();
+        }
+        let packed = writer.into_inner().unwrap();
+
+        let mut expected = vec![];
+        for v in values.iter() {
+            expected.extend(v.pack());
+        }
+        assert_eq!(packed, expected);
+
+        let unpacked: Vec<_> = Reader::new(&packed[..]).map(|v| v.unwrap()).collect();
+        assert_eq!(unpacked, values);
+    }
+
+    #[test]
+    fn write_error() {
+        struct Broken;
+        impl Write for Broken {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Err(io::Error::other("broken"))
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut writer = Writer::new(Broken);
+        writer.pack(&Unpacked::Null).unwrap();
+        let e = writer.flush().unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::Io(_)));
+        let e = Writer::with_capacity(1, Broken)
+            .pack(&Unpacked::Raw(vec![1, 2]))
+            .unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::Io(_)));
+    }
+
+    #[test]
+    fn canonical() {
+        let value = binarypack!({ "b": 1, "a": 2, "c": { 3: null, 1: null, 2: null } });
+        let mut writer = Writer::with_options(
+            vec![],
+            PackOptions {
+                canonical: true,
+                ..Default::default()
+            },
+        );
+        writer.pack(&value).unwrap();
+        assert_eq!(writer.into_inner().unwrap(), value.pack_canonical());
+
+        let options = PackOptions {
+            canonical: true,
+            compact: true,
+            ..Default::default()
+        };
+        let mut writer = Writer::with_capacity_and_options(4, vec![], options.clone());
+        writer.pack(&value).unwrap();
+        assert_eq!(
+            writer.into_inner().unwrap(),
+            value.try_pack_with(&options).unwrap()
+        );
+    }
+}